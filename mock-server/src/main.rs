@@ -2,17 +2,25 @@
 #[macro_use]
 extern crate rocket;
 extern crate rocket_contrib;
+extern crate base64;
 extern crate serde;
 extern crate serde_json;
+extern crate sha2;
 #[macro_use]
 extern crate serde_derive;
 
+use rocket::State;
 use rocket::http::Status;
 use rocket::outcome::Outcome;
-use rocket::request::{self, FromRequest, Request};
-use rocket::response::status;
+use rocket::request::{self, FromRequest, LenientForm, Request};
+use rocket::response::{self, status, Redirect, Responder, Response};
 use rocket_contrib::json::Json;
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct CertificateRequest {
@@ -22,6 +30,25 @@ pub struct CertificateRequest {
     pub environment : String,
     #[serde(rename = "host-name")]
     pub host: Option<String>,
+    #[serde(rename = "valid-for", default)]
+    pub valid_for: Option<u64>,
+    #[serde(default)]
+    pub extensions: Option<Vec<String>>,
+    #[serde(rename = "critical-options", default)]
+    pub critical_options: Option<BTreeMap<String, String>>,
+    #[serde(rename = "key-id", default)]
+    pub key_id: Option<String>,
+}
+
+impl CertificateRequest {
+    /// Options the mock CA would refuse to sign.
+    fn is_valid(&self) -> bool {
+        let extensions = ["no-touch-required", "permit-X11-forwarding", "permit-agent-forwarding", "permit-port-forwarding", "permit-pty", "permit-user-rc"];
+        let critical_options = ["force-command", "source-address"];
+        self.valid_for.map(|seconds| seconds > 0 && seconds <= 24 * 60 * 60).unwrap_or(true)
+            && self.extensions.iter().flatten().all(|extension| extensions.contains(&extension.as_str()))
+            && self.critical_options.iter().flatten().all(|(option, _)| critical_options.contains(&option.as_str()))
+    }
 }
 
 struct Token;
@@ -29,23 +56,504 @@ struct Token;
 impl<'a, 'r> FromRequest<'a, 'r> for Token {
     type Error = ();
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Token, ()> {
-        let auth: Vec<&str> = request.headers().get("Authorization").collect();
-        if auth != vec!["Bearer mock"] {
+        let auth = request.headers().get_one("Authorization").unwrap_or("");
+        if auth == "Bearer mock" {
+            return Outcome::Success(Token);
+        }
+        if !auth.starts_with("DPoP ") {
             return Outcome::Failure((Status::Forbidden, ()));
+        }
+        let token = &auth[5..];
+        let dpop = DpopRequest::from(request);
+        let proofs = match request.guard::<State<Proofs>>().succeeded() {
+            Some(proofs) => proofs,
+            None => return Outcome::Failure((Status::InternalServerError, ())),
         };
-        Outcome::Success(Token)
+        let proof = match dpop.proof {
+            Some(ref proof) => proof,
+            None => return Outcome::Failure((Status::Forbidden, ())),
+        };
+        match verify_proof(&proofs, proof, &dpop.htm, &dpop.htu, Some(token)) {
+            Ok(ref jkt) if token == bound_token(jkt) => Outcome::Success(Token),
+            Err("use_dpop_nonce") => Outcome::Failure((Status::Unauthorized, ())),
+            _ => Outcome::Failure((Status::Forbidden, ())),
+        }
+    }
+}
+
+const DPOP_NONCE: &str = "mock-nonce";
+
+/// The DPoP proof on a request, with the method and url it should be
+/// bound to.
+struct DpopRequest {
+    proof: Option<String>,
+    htm: String,
+    htu: String,
+}
+
+impl DpopRequest {
+    fn from(request: &Request) -> DpopRequest {
+        let host = request.headers().get_one("Host").unwrap_or("localhost:8000");
+        DpopRequest {
+            proof: request.headers().get_one("DPoP").map(|proof| proof.to_string()),
+            htm: request.method().as_str().to_string(),
+            htu: format!("http://{}{}", host, request.uri().path()),
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for DpopRequest {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<DpopRequest, ()> {
+        Outcome::Success(DpopRequest::from(request))
     }
 }
 
-#[post("/oauth/token")]
-fn oauth<'a>() -> Json<Value> {
+/// The url the mock server was reached at, from the Host header.
+struct BaseUrl(String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for BaseUrl {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<BaseUrl, ()> {
+        let host = request.headers().get_one("Host").unwrap_or("localhost:8000");
+        Outcome::Success(BaseUrl(format!("http://{}", host)))
+    }
+}
+
+/// Adds the current `DPoP-Nonce` to a response.
+struct WithNonce<R>(R);
+
+impl<'r, R: Responder<'r>> Responder<'r> for WithNonce<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        Response::build_from(self.0.respond_to(request)?)
+            .raw_header("DPoP-Nonce", DPOP_NONCE)
+            .ok()
+    }
+}
+
+#[derive(Debug)]
+struct WithRetryAfter<R>(R);
+
+impl<'r, R: Responder<'r>> Responder<'r> for WithRetryAfter<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        Response::build_from(self.0.respond_to(request)?)
+            .raw_header("Retry-After", "0")
+            .ok()
+    }
+}
+
+/// The `Idempotency-Key` header, if the request has one.
+struct IdempotencyKey(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for IdempotencyKey {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<IdempotencyKey, ()> {
+        Outcome::Success(IdempotencyKey(request.headers().get_one("Idempotency-Key").map(|key| key.to_string())))
+    }
+}
+
+/// Issue requests by idempotency key, replays of a key must be for
+/// the same request.
+struct Issued {
+    requests: Mutex<HashMap<String, CertificateRequest>>,
+}
+
+/// Ids that have been requested from `/flaky`.
+struct Flaky {
+    seen: Mutex<HashSet<String>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct ProofHeader {
+    typ: String,
+    alg: String,
+    jwk: Value,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct ProofClaims {
+    jti: String,
+    htm: String,
+    htu: String,
+    iat: i64,
+    ath: Option<String>,
+    nonce: Option<String>,
+}
+
+/// Identifiers of DPoP proofs that have already been used.
+struct Proofs {
+    seen: Mutex<HashSet<String>>,
+}
+
+type OAuthResult = Result<Json<Value>, status::Custom<Json<Value>>>;
+
+#[derive(FromForm)]
+struct TokenRequest {
+    grant_type: String,
+    assertion: Option<String>,
+    device_code: Option<String>,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    subject_token: Option<String>,
+    subject_token_type: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct AssertionClaims {
+    iss: String,
+    aud: String,
+    exp: i64,
+    nbf: i64,
+    iat: i64,
+    jti: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct SubjectClaims {
+    iss: String,
+    sub: String,
+    exp: i64,
+}
+
+/// Identifiers of assertions that have already been exchanged.
+struct Assertions {
+    seen: Mutex<HashSet<String>>,
+}
+
+#[derive(FromForm)]
+struct DeviceRequest {
+    client_id: String,
+}
+
+#[derive(FromForm)]
+struct DeviceApproval {
+    user_code: String,
+}
+
+/// Pending device authorizations, device code to user code and
+/// whether the user has approved it.
+struct Devices {
+    next: AtomicUsize,
+    pending: Mutex<HashMap<String, (String, bool)>>,
+}
+
+#[derive(FromForm)]
+struct RevocationRequest {
+    token: String,
+}
+
+#[derive(FromForm)]
+struct AuthorizeRequest {
+    response_type: String,
+    redirect_uri: String,
+    state: String,
+    code_challenge: String,
+    code_challenge_method: String,
+}
+
+/// Issued authorization codes, to the redirect uri and PKCE challenge
+/// they were issued for.
+struct Codes {
+    next: AtomicUsize,
+    issued: Mutex<HashMap<String, (String, String)>>,
+}
+
+/// Outstanding refresh tokens, each is rotated on use.
+struct RefreshTokens {
+    next: AtomicUsize,
+    valid: Mutex<HashSet<String>>,
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("clock").as_secs() as i64
+}
+
+fn decode_part<C: serde::de::DeserializeOwned>(jwt: &str, index: usize) -> Option<C> {
+    let parts: Vec<&str> = jwt.split('.').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let part = base64::decode_config(parts[index], base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice(&part).ok()
+}
+
+fn decode_claims<C: serde::de::DeserializeOwned>(jwt: &str) -> Option<C> {
+    decode_part(jwt, 1)
+}
+
+fn sha256(value: &str) -> String {
+    base64::encode_config(&Sha256::digest(value.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// RFC 7638 thumbprint, serde_json objects serialise with sorted keys.
+fn thumbprint(jwk: &Value) -> Option<String> {
+    let member = |name: &str| jwk.get(name).and_then(|value| value.as_str());
+    let canonical = match member("kty")? {
+        "RSA" => json!({ "e": member("e")?, "kty": "RSA", "n": member("n")? }),
+        "EC" => json!({ "crv": member("crv")?, "kty": "EC", "x": member("x")?, "y": member("y")? }),
+        "OKP" => json!({ "crv": member("crv")?, "kty": "OKP", "x": member("x")? }),
+        _ => return None,
+    };
+    Some(sha256(&canonical.to_string()))
+}
+
+fn bound_token(jkt: &str) -> String {
+    format!("mock-dpop-{}", jkt)
+}
+
+/// Checks the proof is fresh and bound to the request, returning the
+/// thumbprint of its key. The signature is not checked.
+fn verify_proof(proofs: &Proofs, proof: &str, htm: &str, htu: &str, access_token: Option<&str>) -> Result<String, &'static str> {
+    let header: ProofHeader = decode_part(proof, 0).ok_or("invalid_dpop_proof")?;
+    let claims: ProofClaims = decode_claims(proof).ok_or("invalid_dpop_proof")?;
+    if header.typ != "dpop+jwt" || !["RS256", "ES256", "ES384", "EdDSA"].contains(&&header.alg[..]) {
+        return Err("invalid_dpop_proof");
+    }
+    if claims.htm != htm || claims.htu != htu || (claims.iat - now()).abs() > 60 {
+        return Err("invalid_dpop_proof");
+    }
+    if claims.ath != access_token.map(sha256) {
+        return Err("invalid_dpop_proof");
+    }
+    if claims.nonce.as_ref().map(|nonce| &nonce[..]) != Some(DPOP_NONCE) {
+        return Err("use_dpop_nonce");
+    }
+    if !proofs.seen.lock().expect("lock").insert(claims.jti) {
+        return Err("invalid_dpop_proof");
+    }
+    thumbprint(&header.jwk).ok_or("invalid_dpop_proof")
+}
+
+fn oauth_error(error: &str) -> status::Custom<Json<Value>> {
+    status::Custom(Status::BadRequest, Json(json!({
+        "error": error,
+    })))
+}
+
+fn invalid_grant() -> status::Custom<Json<Value>> {
+    oauth_error("invalid_grant")
+}
+
+fn access_token() -> Json<Value> {
+    Json(json!({
+        "access_token": "mock",
+        "expires_in": 3200,
+        "token_type": "Bearer",
+    }))
+}
+
+fn jwt_bearer(assertions: &Assertions, assertion: &str) -> OAuthResult {
+    let claims: AssertionClaims = decode_claims(assertion).ok_or_else(invalid_grant)?;
+    let now = now();
+    if claims.exp <= now || claims.nbf > now || claims.iat > now {
+        return Err(invalid_grant());
+    }
+    let mut seen = assertions.seen.lock().expect("lock");
+    if !seen.insert(claims.jti) {
+        return Err(invalid_grant());
+    }
+    Ok(access_token())
+}
+
+/// Any well formed, unexpired JWT is trusted, the signature is not
+/// checked.
+fn token_exchange(subject_token: &str, subject_token_type: &str) -> OAuthResult {
+    if subject_token_type != "urn:ietf:params:oauth:token-type:jwt" {
+        return Err(oauth_error("invalid_request"));
+    }
+    let claims: SubjectClaims = decode_claims(subject_token).ok_or_else(invalid_grant)?;
+    if claims.exp <= now() {
+        return Err(invalid_grant());
+    }
+    Ok(Json(json!({
+        "access_token": "mock",
+        "issued_token_type": "urn:ietf:params:oauth:token-type:access_token",
+        "expires_in": 3200,
+        "token_type": "Bearer",
+    })))
+}
+
+fn session_token(refresh_tokens: &RefreshTokens) -> Json<Value> {
+    let refresh_token = format!("mock-refresh-{}", refresh_tokens.next.fetch_add(1, Ordering::SeqCst));
+    refresh_tokens.valid.lock().expect("lock").insert(refresh_token.clone());
     Json(json!({
         "access_token": "mock",
         "expires_in": 3200,
         "token_type": "Bearer",
+        "refresh_token": refresh_token,
+    }))
+}
+
+fn refresh_token(refresh_tokens: &RefreshTokens, refresh_token: &str) -> OAuthResult {
+    if !refresh_tokens.valid.lock().expect("lock").remove(refresh_token) {
+        return Err(invalid_grant());
+    }
+    Ok(session_token(refresh_tokens))
+}
+
+fn device_code(devices: &Devices, refresh_tokens: &RefreshTokens, device_code: &str) -> OAuthResult {
+    let mut pending = devices.pending.lock().expect("lock");
+    match pending.get(device_code).map(|(_, approved)| *approved) {
+        None => Err(invalid_grant()),
+        Some(false) => Err(oauth_error("authorization_pending")),
+        Some(true) => {
+            pending.remove(device_code);
+            Ok(session_token(refresh_tokens))
+        },
+    }
+}
+
+fn authorization_code(codes: &Codes, refresh_tokens: &RefreshTokens, code: &str, redirect_uri: &str, verifier: &str) -> OAuthResult {
+    let (expected_uri, challenge) = codes.issued.lock().expect("lock").remove(code).ok_or_else(invalid_grant)?;
+    if expected_uri != redirect_uri || sha256(verifier) != challenge {
+        return Err(invalid_grant());
+    }
+    Ok(session_token(refresh_tokens))
+}
+
+#[post("/oauth/token", data = "<request>")]
+fn oauth(assertions: State<Assertions>, devices: State<Devices>, codes: State<Codes>, refresh_tokens: State<RefreshTokens>, proofs: State<Proofs>, dpop: DpopRequest, request: LenientForm<TokenRequest>) -> WithNonce<OAuthResult> {
+    WithNonce(dpop_token(&proofs, &dpop, || grant(&assertions, &devices, &codes, &refresh_tokens, &request)))
+}
+
+/// Like `oauth`, but the grant is checked, using up any assertion,
+/// before the DPoP proof.
+#[post("/oauth/token-strict", data = "<request>")]
+fn oauth_strict(assertions: State<Assertions>, devices: State<Devices>, codes: State<Codes>, refresh_tokens: State<RefreshTokens>, proofs: State<Proofs>, dpop: DpopRequest, request: LenientForm<TokenRequest>) -> WithNonce<OAuthResult> {
+    let response = grant(&assertions, &devices, &codes, &refresh_tokens, &request);
+    WithNonce(dpop_token(&proofs, &dpop, || response))
+}
+
+/// Binds the token from `grant` to the proof's key, if there is one.
+fn dpop_token<F: FnOnce() -> OAuthResult>(proofs: &Proofs, dpop: &DpopRequest, grant: F) -> OAuthResult {
+    let jkt = match dpop.proof {
+        Some(ref proof) => Some(verify_proof(proofs, proof, &dpop.htm, &dpop.htu, None).map_err(oauth_error)?),
+        None => None,
+    };
+    let mut response = grant()?;
+    if let Some(jkt) = jkt {
+        response.0["access_token"] = json!(bound_token(&jkt));
+        response.0["token_type"] = json!("DPoP");
+    }
+    Ok(response)
+}
+
+fn grant(assertions: &Assertions, devices: &Devices, codes: &Codes, refresh_tokens: &RefreshTokens, request: &TokenRequest) -> OAuthResult {
+    match &request.grant_type[..] {
+        "urn:ietf:params:oauth:grant-type:jwt-bearer" => match request.assertion {
+            Some(ref assertion) => jwt_bearer(assertions, assertion),
+            None => Err(oauth_error("invalid_request")),
+        },
+        "urn:ietf:params:oauth:grant-type:device_code" => match request.device_code {
+            Some(ref code) => device_code(devices, refresh_tokens, code),
+            None => Err(oauth_error("invalid_request")),
+        },
+        "authorization_code" => match (&request.code, &request.redirect_uri, &request.code_verifier) {
+            (Some(code), Some(redirect_uri), Some(verifier)) => authorization_code(codes, refresh_tokens, code, redirect_uri, verifier),
+            _ => Err(oauth_error("invalid_request")),
+        },
+        "refresh_token" => match request.refresh_token {
+            Some(ref token) => refresh_token(refresh_tokens, token),
+            None => Err(oauth_error("invalid_request")),
+        },
+        "urn:ietf:params:oauth:grant-type:token-exchange" => match (&request.subject_token, &request.subject_token_type) {
+            (Some(token), Some(token_type)) => token_exchange(token, token_type),
+            _ => Err(oauth_error("invalid_request")),
+        },
+        _ =>
+            Err(oauth_error("unsupported_grant_type")),
+    }
+}
+
+/// Access tokens are fixed, so only refresh tokens can be revoked.
+#[post("/oauth/revoke", data = "<request>")]
+fn revoke(refresh_tokens: State<RefreshTokens>, request: LenientForm<RevocationRequest>) -> Json<Value> {
+    refresh_tokens.valid.lock().expect("lock").remove(&request.token);
+    Json(json!({}))
+}
+
+/// Stands in for the user logging in with their browser, approving
+/// immediately and redirecting back with a code.
+#[get("/oauth/authorize?<request..>")]
+fn authorize(codes: State<Codes>, request: LenientForm<AuthorizeRequest>) -> Result<Redirect, Status> {
+    if request.response_type != "code" || request.code_challenge_method != "S256" {
+        return Err(Status::BadRequest);
+    }
+    let code = format!("code-{}", codes.next.fetch_add(1, Ordering::SeqCst));
+    codes.issued.lock().expect("lock").insert(code.clone(), (request.redirect_uri.clone(), request.code_challenge.clone()));
+    Ok(Redirect::to(format!("{}?code={}&state={}", request.redirect_uri, code, request.state)))
+}
+
+#[post("/oauth/device/code", data = "<request>")]
+fn device(devices: State<Devices>, request: LenientForm<DeviceRequest>) -> Json<Value> {
+    let n = devices.next.fetch_add(1, Ordering::SeqCst);
+    let device_code = format!("device-{}-{}", request.client_id, n);
+    let user_code = format!("MOCK-{}", n);
+    devices.pending.lock().expect("lock").insert(device_code.clone(), (user_code.clone(), false));
+    Json(json!({
+        "device_code": device_code,
+        "user_code": user_code,
+        "verification_uri": "http://localhost:8000/device",
+        "verification_uri_complete": format!("http://localhost:8000/device?user_code={}", user_code),
+        "expires_in": 600,
+        "interval": 1,
+    }))
+}
+
+/// Stands in for the user approving the login in their browser.
+#[post("/device/approve", data = "<approval>")]
+fn approve(devices: State<Devices>, approval: LenientForm<DeviceApproval>) -> Result<Json<Value>, Status> {
+    let mut pending = devices.pending.lock().expect("lock");
+    for (user_code, approved) in pending.values_mut() {
+        if *user_code == approval.user_code {
+            *approved = true;
+            return Ok(Json(json!({})));
+        }
+    }
+    Err(Status::NotFound)
+}
+
+#[get("/.well-known/oauth-authorization-server")]
+fn metadata(base: BaseUrl) -> Json<Value> {
+    let base = base.0;
+    Json(json!({
+        "issuer": base,
+        "token_endpoint": format!("{}/oauth/token", base),
+        "authorization_endpoint": format!("{}/oauth/authorize", base),
+        "device_authorization_endpoint": format!("{}/oauth/device/code", base),
+        "revocation_endpoint": format!("{}/oauth/revoke", base),
+        "grant_types_supported": [
+            "urn:ietf:params:oauth:grant-type:jwt-bearer",
+            "urn:ietf:params:oauth:grant-type:device_code",
+            "urn:ietf:params:oauth:grant-type:token-exchange",
+            "authorization_code",
+            "refresh_token",
+        ],
+        "code_challenge_methods_supported": ["S256"],
+        "token_endpoint_auth_signing_alg_values_supported": ["RS256", "ES256", "ES384", "EdDSA"],
+        "dpop_signing_alg_values_supported": ["RS256", "ES256", "ES384", "EdDSA"],
+    }))
+}
+
+/// A deployment that only publishes OpenID metadata, under a path.
+#[get("/oidc/.well-known/openid-configuration")]
+fn openid_configuration(base: BaseUrl) -> Json<Value> {
+    let base = base.0;
+    Json(json!({
+        "issuer": format!("{}/oidc", base),
+        "token_endpoint": format!("{}/oauth/token", base),
+        "authorization_endpoint": format!("{}/oauth/authorize", base),
     }))
 }
 
+/// Metadata published under a path, but for the server's issuer.
+#[get("/mismatched/.well-known/oauth-authorization-server")]
+fn mismatched_metadata(base: BaseUrl) -> Json<Value> {
+    metadata(base)
+}
+
 #[get("/userinfo")]
 fn userinfo(_token: Token) -> Json<Value> {
     Json(json!({
@@ -62,11 +570,52 @@ fn keys(_token: Token, _environment: String) -> Json<Value> {
     }))
 }
 
-#[post("/issue", data = "<_request>")]
-fn issue(_token: Token, _request: Json<CertificateRequest>) -> Json<Value> {
-    Json(json!({
+#[post("/issue", data = "<request>")]
+fn issue(_token: Token, issued: State<Issued>, key: IdempotencyKey, request: Json<CertificateRequest>) -> Result<Json<Value>, Status> {
+    if !request.is_valid() {
+        return Err(Status::BadRequest);
+    }
+    if let Some(key) = key.0 {
+        let mut requests = issued.requests.lock().unwrap();
+        match requests.get(&key) {
+            Some(previous) if *previous != request.0 => return Err(Status::UnprocessableEntity),
+            Some(_) => (),
+            None => { requests.insert(key, request.0.clone()); },
+        }
+    }
+    Ok(Json(json!({
         "certificate": "ssh-rsa-cert-v01@openssh.com AAAAHHNzaC1yc2EtY2VydC12MDFAb3BlbnNzaC5jb20AAAAgRub3rrMJC5jR1C2AU0vhYV8pPM1UFsAglyKwhkKxKHIAAAADAQABAAABAQDI6z6dBtqnv2F0kqD8gnRMPkAoOdNpaa5qnx3UyXM8RApmBY180RKTSLzTRcrFFYxDfHLOFWw/V0JM4bLwNaHhhuYGllYqb2qHlVs7KgoytBGy//xtRMemkX2BY5UwD8iqw+5a45xqoddL8hTRk77ploFa7ItgTVVPD30l3hZHWWQr2/eINI9G41nLfQZkOYjkNf1s8DJsHI8FunKgp8lwGMUZaAq9mnYpVHBQX6LSjZiBUN9pIkoDO5+08AN6RIUIgJ9Q0T0AGLRcMQKTx1fkeV7wkreJF2TmBVUE0ZOIDQEOOis1+YigT4JAqrDI0+OYGzEGu2tHFRemjs3uvQLbAAAAAAAAAAAAAAABAAAAB2V4YW1wbGUAAAAIAAAABHJvb3QAAAAAW3YpyAAAAABdVgwcAAAAAAAAAIIAAAAVcGVybWl0LVgxMS1mb3J3YXJkaW5nAAAAAAAAABdwZXJtaXQtYWdlbnQtZm9yd2FyZGluZwAAAAAAAAAWcGVybWl0LXBvcnQtZm9yd2FyZGluZwAAAAAAAAAKcGVybWl0LXB0eQAAAAAAAAAOcGVybWl0LXVzZXItcmMAAAAAAAAAAAAAAhcAAAAHc3NoLXJzYQAAAAMBAAEAAAIBAOXe63J9QvApVTi3UZiMs4KwXQF35rODzRWIkWlBHdNEslbx3n0pxDeIzFK9oYAj91q3yjBkvKAZAeNfzxltviW4VNawRcZp7BuNK9N2jcibdd8vppkk20xUx9QnSeisu4G/c4e5QOeKDBgknUi7Idk0PQHpBT0X0jt06TGBqQ1OGahZdF6pAgorVkL2nl5EzL60bE6l+dPBcSAZeVOmhrkS4lXXSZYlLoLINV0yn6oseT9iiHMdviXuCrsHi9WQ9PytzXei6KD0Kk/bawtH00KLCA5tO6ecqyVdMNCfosBDRYpUZCEP4wvOHas7nGhhlSbMH1ncP7Sxr/1gtjlWHCVtNztQzGg0wrSMOsv2KYSyXrnbdOKdR4qlFbdqwn1hcnF0CBAeVu7p5js6BCpbe5lSj8SKmpCMZq45wjudYbUzufnpS2rOQsAiEdTdP2iraQBVA/a10yHFLjrpZH5w47iXTIif9DcIaF5zFeUD/QgsqAg3gbmPH3m6hriiQyFWVWl5rNiXPQ4jGnaegrBxZsyWV3o690z1tIZg1SyD1qjwMUW1rqlDtdpJbMeuJXauuxUqXtTDtPu/TP+hBdhp68gkHt1E1ieUryNzAm7ZU5xEJyorSBc1qWcwy9wZhOfojlWbpzKRuJR8BIB7l1/ywcRWaK52ReXxnckY9nJHPg2lAAACDwAAAAdzc2gtcnNhAAACAMljKv0kSQozGB/hSLHo45hXgdFSPQ+lArSY02X9UDpyxfXhcrpWFuPyp+5nXXaiULrSL49srfBfVrodJpJm55Gx0suAFsMIOeFVEIp/fZ7/I66y22OV7NgtlKoeLhMUp/Tf3ywfVsXBpXCqAChVImmBRq+P/GCmwmV1QNf3fyTiMTAB33agwYQxsPYSDCWNQGKsLGhxBMO/KVYGSBDCm+Qj/UO4ZrAV/Y+3PHUM4O/4UIZcFRydZHdQT+wVycdPf+ySFvdLL7AoBxu544tdN+R8lehGgB09j6GLbCjppr09nboT6eYEUmjgHj9N9n/hj1KaIeGt+q+1OxOwkHPMsLzsOmxEF2nFDahZ8/+Pade6DmC2BrYBPlo8QPDkPb/FNR0b45QCljWFdNALB/ag52sJ7EcCIeZ2T/mx8tSNz5Y1am/e6kebhMjbztQD1nFpaGI1nxw+MMd+9vBM9dapTJuKu55dt/oZ6XOVpnabGddoJX+zOS7M8c7ssU33IUKDiRCSyGlp1OZslSCCpkYurnRWNB3CWwQ8OkswPqud9dSamVCppdcOeLA2EQvYDGSYCDs4nVsy7Zkiruk+EInQwTzAsKkGc3gVPSQrmVooWgjAluUeu8j1f9xPSO6IWvyBz1de3olnOpPfIMzx21jCyvRPX/yKvd7etGT5BnhVck2b test-cert",
-    }))
+    })))
+}
+
+/// Unavailable the first time each `id` is requested.
+#[get("/flaky/<id>")]
+fn flaky(_token: Token, flaky: State<Flaky>, id: String) -> Result<Json<Value>, WithRetryAfter<status::Custom<&'static str>>> {
+    if flaky.seen.lock().unwrap().insert(id) {
+        return Err(WithRetryAfter(status::Custom(Status::ServiceUnavailable, "Unavailable")));
+    }
+    Ok(Json(json!({
+        "sub": "1",
+    })))
+}
+
+/// Responds with `code`, to exercise the client's status handling,
+/// gateway errors have plain text bodies as a proxy would.
+#[get("/status/<code>")]
+fn status_code(_token: Token, code: u16) -> Option<status::Custom<Result<Json<Value>, String>>> {
+    let status = Status::from_code(code)?;
+    let body = match code {
+        502 => Err(status.reason.to_string()),
+        _ => Ok(Json(json!({ "error": format!("status-{}", code) }))),
+    };
+    Some(status::Custom(status, body))
+}
+
+#[catch(401)]
+fn unauthorized() -> WithNonce<status::Custom<Json<Value>>> {
+    WithNonce(status::Custom(Status::Unauthorized, Json(json!({
+       "error": "use_dpop_nonce"
+    }))))
 }
 
 #[catch(403)]
@@ -78,12 +627,30 @@ fn forbidden() -> status::Custom<Json<Value>> {
 
 fn main() {
     rocket::ignite()
+        .manage(Assertions { seen: Mutex::new(HashSet::new()) })
+        .manage(Devices { next: AtomicUsize::new(0), pending: Mutex::new(HashMap::new()) })
+        .manage(Codes { next: AtomicUsize::new(0), issued: Mutex::new(HashMap::new()) })
+        .manage(Proofs { seen: Mutex::new(HashSet::new()) })
+        .manage(RefreshTokens { next: AtomicUsize::new(0), valid: Mutex::new(HashSet::new()) })
+        .manage(Issued { requests: Mutex::new(HashMap::new()) })
+        .manage(Flaky { seen: Mutex::new(HashSet::new()) })
         .mount("/", routes![
             oauth,
+            oauth_strict,
+            device,
+            approve,
+            authorize,
+            revoke,
+            metadata,
+            openid_configuration,
+            mismatched_metadata,
             userinfo,
             keys,
             issue,
+            flaky,
+            status_code,
         ]).register(catchers![
+            unauthorized,
             forbidden
         ]).launch();
 }
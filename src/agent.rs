@@ -44,6 +44,50 @@ pub struct Agent {
     stream: UnixStream,
 }
 
+/// Identities added by smith have this comment, or it followed by
+/// `:` and the certificate's comment.
+pub const COMMENT: &str = "smith";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Identity {
+    pub blob: Vec<u8>,
+    pub comment: String,
+}
+
+impl Identity {
+    pub fn is_smith(&self) -> bool {
+        self.comment == COMMENT || self.comment.starts_with(&format!("{}:", COMMENT))
+    }
+
+    /// Parse an SSH_AGENT_IDENTITIES_ANSWER.
+    pub fn parse_answer(bytes: &[u8]) -> Result<Vec<Identity>, ProtocolError> {
+        let invalid = || ProtocolError::InvalidResponse(bytes.to_vec());
+        if bytes.first() != Some(&Reply::IdentitiesAnswerReply.to_u8()) {
+            return match bytes.first() {
+                Some(byte) => Err(ProtocolError::UnexpectedReply(Reply::from_u8(*byte)?)),
+                None => Err(invalid()),
+            };
+        }
+        let mut reader = Cursor::new(&bytes[1..]);
+        let count = codec::decode_uint32(&mut reader).map_err(|_e| invalid())?;
+        let mut identities = vec![];
+        for _ in 0..count {
+            let blob = codec::decode_bytes(&mut reader).map_err(|_e| invalid())?;
+            let comment = codec::decode_bytes(&mut reader).map_err(|_e| invalid())?;
+            identities.push(Identity { blob, comment: String::from_utf8_lossy(&comment).to_string() });
+        }
+        Ok(identities)
+    }
+}
+
+/// The agent comment for a certificate with `comment`.
+pub fn comment(comment: &Option<String>) -> String {
+    match comment {
+        Some(comment) => format!("{}:{}", COMMENT, comment),
+        None => COMMENT.to_string(),
+    }
+}
+
 impl Agent {
     pub fn connect() -> Option<Agent> {
         let path = env::var("SSH_AUTH_SOCK").ok()?;
@@ -51,6 +95,12 @@ impl Agent {
     }
 
     pub fn send(&mut self, message: Message, payload: &[u8]) -> Result<Reply, ProtocolError> {
+        let result = self.request(message, payload)?;
+        Reply::from_bytes(&result)
+    }
+
+    /// Send a message, returning the raw reply.
+    pub fn request(&mut self, message: Message, payload: &[u8]) -> Result<Vec<u8>, ProtocolError> {
         let packet = message.packet(payload);
         self.stream.write_all(&packet)?;
         let mut result_size = [0; 4];
@@ -58,7 +108,35 @@ impl Agent {
         let size = BigEndian::read_u32(&result_size);
         let mut result = vec![0; size as usize];
         self.stream.read_exact(&mut result)?;
-        Reply::from_bytes(&result)
+        Ok(result)
+    }
+
+    pub fn identities(&mut self) -> Result<Vec<Identity>, ProtocolError> {
+        let result = self.request(Message::RequestIdentitiesMessage, &[])?;
+        Identity::parse_answer(&result)
+    }
+
+    pub fn remove_identity(&mut self, identity: &Identity) -> Result<(), ProtocolError> {
+        let mut buffer = Cursor::new(Vec::<u8>::new());
+        codec::encode_bytes(&mut buffer, &identity.blob)?;
+        let reply = self.send(Message::RemoveIdentityMessage, &buffer.into_inner())?;
+        if reply != Reply::SuccessReply {
+            return Err(ProtocolError::UnexpectedReply(reply));
+        }
+        Ok(())
+    }
+
+    /// Remove the keys and certificates smith has added, returning how
+    /// many were removed.
+    pub fn remove_smith_identities(&mut self) -> Result<usize, ProtocolError> {
+        let identities = self.identities()?
+            .into_iter()
+            .filter(|identity| identity.is_smith())
+            .collect::<Vec<_>>();
+        for identity in identities.iter() {
+            self.remove_identity(identity)?;
+        }
+        Ok(identities.len())
     }
 
     pub fn add_private_key(&mut self, key: &Rsa<Private>, comment: &Option<String>) -> Result<(), ProtocolError> {
@@ -71,8 +149,7 @@ impl Agent {
         codec::encode_bignum(&mut buffer, key.iqmp().expect("iqmp"))?;
         codec::encode_bignum(&mut buffer, key.p().expect("p"))?;
         codec::encode_bignum(&mut buffer, key.q().expect("q"))?;
-        // FUTURE: Better default comment.
-        codec::encode_string(&mut buffer, comment.as_ref().unwrap_or(&"foo".to_string()))?;
+        codec::encode_string(&mut buffer, comment.as_ref().map(|c| &c[..]).unwrap_or(COMMENT))?;
         let reply = self.send(Message::AddIdentityMessage, &buffer.into_inner())?;
         if reply != Reply::SuccessReply {
             return Err(ProtocolError::UnexpectedReply(reply));
@@ -82,7 +159,8 @@ impl Agent {
 
     pub fn add_certificate(&mut self, key: &Rsa<Private>, certificate: &Certificate) -> Result<(), ProtocolError> {
         let certificate = certificate.deconstruct().ok_or(ProtocolError::InvalidCertificate)?;
-        self.add_private_key(key, &certificate.comment)?;
+        let comment = comment(&certificate.comment);
+        self.add_private_key(key, &Some(comment.clone()))?;
         let mut buffer = Cursor::new(vec![0 as u8; 100]);
         codec::encode_string(&mut buffer, &certificate.key_type)?;
         codec::encode_bytes(&mut buffer, &certificate.blob)?;
@@ -90,8 +168,7 @@ impl Agent {
         codec::encode_bignum(&mut buffer, key.iqmp().ok_or(ProtocolError::InvalidCertificate)?)?;
         codec::encode_bignum(&mut buffer, key.p().ok_or(ProtocolError::InvalidCertificate)?)?;
         codec::encode_bignum(&mut buffer, key.q().ok_or(ProtocolError::InvalidCertificate)?)?;
-        // FUTURE: Better default comment.
-        codec::encode_string(&mut buffer, &certificate.comment.unwrap_or("smith".to_string()))?;
+        codec::encode_string(&mut buffer, &comment)?;
         let reply = self.send(Message::AddIdentityMessage, &buffer.into_inner())?;
         if reply != Reply::SuccessReply {
             return Err(ProtocolError::UnexpectedReply(reply));
@@ -102,13 +179,17 @@ impl Agent {
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Message {
+    RequestIdentitiesMessage,
     AddIdentityMessage,
+    RemoveIdentityMessage,
 }
 
 impl Message {
     pub fn to_u8(&self) -> u8 {
         match self {
+            Message::RequestIdentitiesMessage => 11,
             Message::AddIdentityMessage => 17,
+            Message::RemoveIdentityMessage => 18,
         }
     }
 
@@ -161,3 +242,33 @@ impl Reply {
         Reply::from_u8(bytes[0])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comment() {
+        assert_eq!(comment(&None), "smith");
+        assert_eq!(comment(&Some("test-cert".to_string())), "smith:test-cert");
+        assert!(Identity { blob: vec![], comment: comment(&Some("test-cert".to_string())) }.is_smith());
+        assert!(!Identity { blob: vec![], comment: "smithers".to_string() }.is_smith());
+    }
+
+    #[test]
+    fn test_parse_answer() {
+        let mut buffer = Cursor::new(vec![Reply::IdentitiesAnswerReply.to_u8()]);
+        buffer.set_position(1);
+        codec::encode_uint32(&mut buffer, 2).expect("Encode should succeed.");
+        codec::encode_bytes(&mut buffer, &[1, 2, 3]).expect("Encode should succeed.");
+        codec::encode_string(&mut buffer, "smith").expect("Encode should succeed.");
+        codec::encode_bytes(&mut buffer, &[4]).expect("Encode should succeed.");
+        codec::encode_string(&mut buffer, "me@laptop").expect("Encode should succeed.");
+        let identities = Identity::parse_answer(&buffer.into_inner()).expect("Parse should succeed.");
+        assert_eq!(identities, vec![
+            Identity { blob: vec![1, 2, 3], comment: "smith".to_string() },
+            Identity { blob: vec![4], comment: "me@laptop".to_string() },
+        ]);
+        assert!(Identity::parse_answer(&[Reply::IdentitiesAnswerReply.to_u8(), 0, 0, 0, 1]).is_err());
+    }
+}
@@ -4,33 +4,59 @@ use crate::data::{
     Certificate,
     Environment,
     HostName,
+    IssueRequest,
     PublicKey,
     Principal,
     UserInfo,
 };
+use crate::http;
 use crate::oauth2;
-use reqwest::header;
-use serde_json::{Value, json};
+use crate::pinning::PinError;
+use crate::retry::{self, Attempt};
+use crate::transport::{PinnedTransport, ReqwestTransport, Request, Response, Transport};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use serde_json::Value;
 use std::fmt;
+use std::sync::{Arc, Mutex};
 
+/// Scope needed to read the user's profile.
+pub const PROFILE_SCOPE: &str = "profile";
+
+/// Scope needed to read CA keys and issue certificates.
+pub const CA_SCOPE: &str = "ca";
+
+/// A smith API client, `Api` is `Send + Sync` and can be shared
+/// between threads, see `oauth2::Refresher` to keep its token fresh.
 pub struct Api {
     pub configuration: Configuration,
-    pub oauth2: oauth2::Store,
-    pub client: reqwest::Client,
+    pub oauth2: Arc<oauth2::Store>,
+    pub transport: Arc<dyn Transport>,
+    /// The latest `DPoP-Nonce` from the API.
+    dpop_nonce: Mutex<Option<String>>,
 }
 
 
 #[derive(Debug)]
 pub enum Error {
     RequestError(reqwest::Error),
+    InvalidEndpoint(String),
     InvalidStatusCode(reqwest::StatusCode),
     ServerError(reqwest::StatusCode, ServerError),
+    UnavailableError(reqwest::StatusCode, ServerError),
     ClientError(reqwest::StatusCode, ServerError),
+    AuthenticationError(reqwest::StatusCode, ServerError),
     PermissionError(reqwest::StatusCode, ServerError),
     NotFoundError(reqwest::StatusCode, ServerError),
+    ConflictError(reqwest::StatusCode, ServerError),
+    RateLimitError(reqwest::StatusCode, ServerError),
     CouldNotParseErrorResponse(reqwest::StatusCode, reqwest::Error),
-    CouldNotParseResponse(reqwest::Error),
+    CouldNotParseResponse(serde_json::Error),
+    InvalidHeaderError(String),
     GrantError(oauth2::GrantError),
+    PinningError(PinError),
+    HttpError(http::Error),
+    /// `AsyncApi` only uses bearer tokens.
+    UnsupportedDpopError,
 }
 
 impl fmt::Display for Error {
@@ -38,20 +64,44 @@ impl fmt::Display for Error {
         match self {
             Error::RequestError(_) =>
               write!(f, "Request error trying to contact the server, please check connectivity to Smith and retry request."),
+            Error::InvalidEndpoint(endpoint) =>
+              write!(f, "The configured endpoint '{}' is not a valid url, check SMITH_ENDPOINT.", endpoint),
             Error::InvalidStatusCode(_) =>
               write!(f, "Server responded with an invalid status code, please check connectivity to Smith and retry request."),
             Error::ServerError(_, _) =>
               write!(f, "Request failed, please check connectivity to Smith and retry request."),
+            Error::UnavailableError(_, _) =>
+              write!(f, "Smith is temporarily unavailable, please retry request shortly."),
             Error::ClientError(_, _) =>
               write!(f, "Request failed, the request was invalid, check your command/arguments."),
+            Error::AuthenticationError(_, _) =>
+              write!(f, "Request was not authenticated, check your API credentials are valid."),
             Error::NotFoundError(_, _) =>
               write!(f, "Request failed, the requested resource was not found, check your command/arguments."),
             Error::PermissionError(_, _) =>
               write!(f, "Request denied, you don't have access to this resource."),
+            Error::ConflictError(_, _) =>
+              write!(f, "Request conflicted with another request, please retry request."),
+            Error::RateLimitError(_, _) =>
+              write!(f, "Too many requests, please wait and retry request."),
             Error::CouldNotParseErrorResponse(_, _) =>
               write!(f, "Invalid error response from server, request failed but we couldn't decode the error, please check connectivity to Smith and retry request."),
             Error::CouldNotParseResponse(_) =>
               write!(f, "Invalid response from server, please check connectivity to Smith and retry request."),
+            Error::InvalidHeaderError(name) =>
+              write!(f, "Could not build the {} header for the request, check your credentials are valid.", name),
+            Error::GrantError(oauth2::GrantError::PinningError(e)) | Error::PinningError(e) =>
+              write!(f, "{}", e),
+            Error::HttpError(e) =>
+              write!(f, "{}", e),
+            Error::UnsupportedDpopError =>
+              write!(f, "DPoP is not supported by the async client, unset SMITH_DPOP or set it to disabled to use bearer tokens."),
+            Error::GrantError(oauth2::GrantError::DiscoveryError(e)) =>
+              write!(f, "{}", e),
+            Error::GrantError(oauth2::GrantError::UnsupportedAlgorithmError(algorithm, endpoint)) =>
+              write!(f, "{} credentials are not supported by {}, check your credentials.json is for this endpoint.", algorithm, endpoint),
+            Error::GrantError(oauth2::GrantError::LoginRequired) =>
+              write!(f, "Your login session has expired, run `smith login` to log in again."),
             Error::GrantError(_) =>
               write!(f, "OAuth2 grant failed, check your API credentials are valid."),
         }
@@ -64,169 +114,333 @@ pub struct ServerError {
     error: String,
 }
 
+impl ServerError {
+    pub fn error(&self) -> &str {
+        &self.error
+    }
+
+    /// The error from a response body, bodies that aren't a smith
+    /// error (e.g. from a proxy) are described by the status.
+    pub(crate) fn from_body(status: reqwest::StatusCode, body: &str) -> ServerError {
+        serde_json::from_str(body).unwrap_or_else(|_| ServerError {
+            error: status.canonical_reason().unwrap_or("unknown").to_string(),
+        })
+    }
+}
+
 
 impl Api {
     pub fn new(configuration: Configuration) -> Api {
-        let client = reqwest::Client::new();
-        // FUTURE: support sharing client between oauth and api.
-        let oauth2 = configuration.oauth2.initialise();
-        Api { configuration, oauth2, client }
-    }
-
-    pub fn get(&mut self, url: &str) -> Result<reqwest::Response, Error> {
-        let token = self.oauth2.grant().map_err(|e| Error::GrantError(e))?;
-        let mut response = self.client
-            .get(&format!("{}/{}", self.configuration.endpoint, url))
-            .bearer_auth(&token.value)
-            .header(header::ACCEPT, "application/json")
-            .send()
-            .map_err(|e| Error::RequestError(e))?;
-        match response.status() {
-            reqwest::StatusCode::OK => Ok(response),
-            reqwest::StatusCode::BAD_REQUEST => {
-                let error: ServerError = response
-                    .json()
-                    .map_err(|e| Error::CouldNotParseErrorResponse(response.status(), e))?;
-                Err(Error::ClientError(response.status(), error))
-            },
-            reqwest::StatusCode::FORBIDDEN => {
-                let error: ServerError = response
-                    .json()
-                    .map_err(|e| Error::CouldNotParseErrorResponse(response.status(), e))?;
-                Err(Error::PermissionError(response.status(), error))
-            },
-            reqwest::StatusCode::INTERNAL_SERVER_ERROR => {
-                let error: ServerError = response
-                    .json()
-                    .map_err(|e| Error::CouldNotParseErrorResponse(response.status(), e))?;
-                Err(Error::ServerError(response.status(), error))
-            },
-            reqwest::StatusCode::NOT_FOUND => {
-                let error: ServerError = response
-                    .json()
-                    .map_err(|e| Error::CouldNotParseErrorResponse(response.status(), e))?;
-                Err(Error::NotFoundError(response.status(), error))
-            },
-            s => Err(Error::InvalidStatusCode(s)),
+        let transport: Arc<dyn Transport> = match configuration.oauth2.pins {
+            Some(ref pins) => Arc::new(PinnedTransport::new(pins.clone())),
+            None => Arc::new(ReqwestTransport::new(configuration.client.clone())),
+        };
+        Api::with_transport(configuration, transport)
+    }
+
+    /// An `Api` sending its requests with `transport`, tokens are
+    /// still obtained with the configured client.
+    pub fn with_transport(configuration: Configuration, transport: Arc<dyn Transport>) -> Api {
+        let oauth2 = Arc::new(configuration.oauth2.initialise_with(configuration.client.clone()));
+        let dpop_nonce = Mutex::new(None);
+        Api { configuration, oauth2, transport, dpop_nonce }
+    }
+
+    /// The scopes to request for an operation that needs `scopes`.
+    fn scopes(&self, scopes: &[&str]) -> Vec<String> {
+        self.configuration.scopes.clone().unwrap_or_else(|| scopes.iter().map(|scope| scope.to_string()).collect())
+    }
+
+    /// The endpoint with `path` appended, each segment is encoded.
+    pub fn url(&self, path: &[&str]) -> Result<reqwest::Url, Error> {
+        url(&self.configuration.endpoint, path)
+    }
+
+    /// Make an authorized request with a token for `scopes`, only
+    /// successful responses are returned. Requests that fail
+    /// transiently are retried, POSTs carry an `Idempotency-Key` so
+    /// the server acts on them once however many times they're sent.
+    pub fn execute(&self, method: reqwest::Method, path: &[&str], scopes: &[&str], body: Option<&Value>) -> Result<Response, Error> {
+        let url = self.url(path)?;
+        let scopes = self.scopes(scopes);
+        let idempotency_key = match method {
+            reqwest::Method::POST => Some(oauth2::random(16).map_err(|e| Error::GrantError(e))?),
+            _ => None,
+        };
+        self.configuration.oauth2.retry.run(|| {
+            let response = self.authorized(&method, &url, &scopes, body, idempotency_key.as_ref().map(|key| key.as_str())).map_err(attempt)?;
+            let retry_after = retry::retry_after(&response.headers);
+            check(response).map_err(|e| match e {
+                Error::RateLimitError(_, _) | Error::UnavailableError(_, _) => Attempt::Retry(e, retry_after),
+                e => Attempt::Fail(e),
+            })
+        })
+    }
+
+    /// Send the request, a rejected token is renewed and the request
+    /// sent once more.
+    fn authorized(&self, method: &reqwest::Method, url: &reqwest::Url, scopes: &[String], body: Option<&Value>, idempotency_key: Option<&str>) -> Result<Response, Error> {
+        let token = self.oauth2.grant_for(scopes).map_err(|e| Error::GrantError(e))?;
+        let mut response = self.send(method, url, body, idempotency_key, &token)?;
+        let dpop = self.oauth2.endpoints().map_err(|e| Error::GrantError(e))?.dpop;
+        if response.status == reqwest::StatusCode::UNAUTHORIZED && dpop && response.headers.contains_key("DPoP-Nonce") {
+            response = self.send(method, url, body, idempotency_key, &token)?;
+        }
+        if response.status == reqwest::StatusCode::UNAUTHORIZED {
+            let token = self.oauth2.renew_for(scopes, true).map_err(|e| Error::GrantError(e))?;
+            response = self.send(method, url, body, idempotency_key, &token)?;
         }
+        Ok(response)
     }
 
-    pub fn post(&mut self, url: &str, body: &Value) -> Result<reqwest::Response, Error> {
-        let token = self.oauth2.grant().map_err(|e| Error::GrantError(e))?;
-        let mut response = self.client
-            .post(&format!("{}/{}", self.configuration.endpoint, url))
-            .bearer_auth(&token.value)
-            .header(header::ACCEPT, "application/json")
-            .header(header::CONTENT_TYPE, "application/json")
-            .json(body)
-            .send()
-            .map_err(|e| Error::RequestError(e))?;
-        match response.status() {
-            reqwest::StatusCode::OK => Ok(response),
-            reqwest::StatusCode::BAD_REQUEST => {
-                let error: ServerError = response
-                    .json()
-                    .map_err(|e| Error::CouldNotParseErrorResponse(response.status(), e))?;
-                Err(Error::ClientError(response.status(), error))
+    fn send(&self, method: &reqwest::Method, url: &reqwest::Url, body: Option<&Value>, idempotency_key: Option<&str>, token: &oauth2::AccessToken) -> Result<Response, Error> {
+        let nonce = self.dpop_nonce.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let proof = self.oauth2.proof(method.as_str(), url.as_str(), Some(&token.value), nonce).map_err(|e| Error::GrantError(e))?;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+        match proof {
+            Some(proof) => {
+                headers.insert(header::AUTHORIZATION, header_value("Authorization", &format!("DPoP {}", token.value))?);
+                headers.insert("DPoP", header_value("DPoP", &proof)?);
             },
-            reqwest::StatusCode::FORBIDDEN => {
-                let error: ServerError = response
-                    .json()
-                    .map_err(|e| Error::CouldNotParseErrorResponse(response.status(), e))?;
-                Err(Error::PermissionError(response.status(), error))
+            None => {
+                headers.insert(header::AUTHORIZATION, header_value("Authorization", &format!("Bearer {}", token.value))?);
             },
-            reqwest::StatusCode::INTERNAL_SERVER_ERROR => {
-                let error: ServerError = response
-                    .json()
-                    .map_err(|e| Error::CouldNotParseErrorResponse(response.status(), e))?;
-                Err(Error::ServerError(response.status(), error))
-            },
-            reqwest::StatusCode::NOT_FOUND => {
-                let error: ServerError = response
-                    .json()
-                    .map_err(|e| Error::CouldNotParseErrorResponse(response.status(), e))?;
-                Err(Error::NotFoundError(response.status(), error))
-            },
-            s => Err(Error::InvalidStatusCode(s)),
         }
+        if let Some(idempotency_key) = idempotency_key {
+            headers.insert("Idempotency-Key", header_value("Idempotency-Key", idempotency_key)?);
+        }
+        if body.is_some() {
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        }
+        let response = self.transport.send(Request {
+            method: method.clone(),
+            url: url.clone(),
+            headers: headers,
+            body: body.map(|body| body.to_string().into_bytes()),
+        })?;
+        if let Some(nonce) = response.headers.get("DPoP-Nonce").and_then(|nonce| nonce.to_str().ok()) {
+            *self.dpop_nonce.lock().unwrap_or_else(|e| e.into_inner()) = Some(nonce.to_string());
+        }
+        Ok(response)
     }
 
-    pub fn whoami(&mut self) -> Result<UserInfo, Error> {
-        self.get("userinfo")?
-            .json()
-            .map_err(|e| Error::CouldNotParseResponse(e))
+    /// GET `path` with a token for `scopes`.
+    pub fn get(&self, path: &[&str], scopes: &[&str]) -> Result<Response, Error> {
+        self.execute(reqwest::Method::GET, path, scopes, None)
+    }
 
+    /// POST `body` to `path` with a token for `scopes`.
+    pub fn post(&self, path: &[&str], scopes: &[&str], body: &Value) -> Result<Response, Error> {
+        self.execute(reqwest::Method::POST, path, scopes, Some(body))
     }
 
-    pub fn keys(&mut self, environment: &Environment) -> Result<AuthorityPublicKeys, Error> {
-        self.get(&format!("environment/public-keys/{}", environment.name))?
-            .json()
-            .map_err(|e| Error::CouldNotParseResponse(e))
+    pub fn whoami(&self) -> Result<UserInfo, Error> {
+        self.get(&["userinfo"], &[PROFILE_SCOPE])?.json()
     }
 
-    pub fn issue(&mut self, environment: &Environment, public_key: &PublicKey, principals: &[Principal], host: &Option<HostName>) -> Result<Certificate, Error> {
-        self.post("issue", &json!({
-            "public-key": public_key.encoded,
-            "principals": principals.iter().map(|p| &p.name).collect::<Vec<_>>(),
-            "environment": environment.name,
-            "host-name": host.as_ref().map(|h| &h.host),
-        }))?
-            .json()
-            .map_err(|e| Error::CouldNotParseResponse(e))
+    pub fn keys(&self, environment: &Environment) -> Result<AuthorityPublicKeys, Error> {
+        self.get(&["environment", "public-keys", &environment.name], &[CA_SCOPE])?.json()
+    }
+
+    pub fn issue(&self, environment: &Environment, public_key: &PublicKey, principals: &[Principal], host: &Option<HostName>) -> Result<Certificate, Error> {
+        self.issue_with(&IssueRequest::new(environment, public_key).principals(principals).host(host))
+    }
+
+    /// Issue a certificate with options beyond the environment's
+    /// defaults, e.g. a shorter validity or a forced command.
+    pub fn issue_with(&self, request: &IssueRequest) -> Result<Certificate, Error> {
+        self.post(&["issue"], &[CA_SCOPE], &request.to_json())?.json()
+    }
+
+}
+
+/// Connection failures and timeouts are worth retrying, failed grants
+/// have already been retried.
+pub(crate) fn attempt(error: Error) -> Attempt<Error> {
+    let retryable = match &error {
+        Error::RequestError(e) => e.is_http() || e.is_timeout(),
+        Error::PinningError(e) => e.is_transient(),
+        _ => false,
+    };
+    if retryable { Attempt::Retry(error, None) } else { Attempt::Fail(error) }
+}
+
+pub(crate) fn url(endpoint: &str, path: &[&str]) -> Result<reqwest::Url, Error> {
+    let invalid = || Error::InvalidEndpoint(endpoint.to_string());
+    let mut url = reqwest::Url::parse(endpoint).map_err(|_e| invalid())?;
+    url.path_segments_mut().map_err(|_e| invalid())?.pop_if_empty().extend(path);
+    Ok(url)
+}
+
+/// Map unsuccessful responses to the error for their status.
+pub(crate) fn check(response: Response) -> Result<Response, Error> {
+    let status = response.status;
+    if status.is_success() {
+        return Ok(response);
     }
+    if !status.is_client_error() && !status.is_server_error() {
+        return Err(Error::InvalidStatusCode(status));
+    }
+    let error = ServerError::from_body(status, &String::from_utf8_lossy(&response.body));
+    Err(status_error(status, error))
+}
 
+pub(crate) fn header_value(name: &str, value: &str) -> Result<HeaderValue, Error> {
+    HeaderValue::from_str(value).map_err(|_e| Error::InvalidHeaderError(name.to_string()))
+}
+
+/// The error for an unsuccessful status.
+pub(crate) fn status_error(status: reqwest::StatusCode, error: ServerError) -> Error {
+    match status {
+        reqwest::StatusCode::UNAUTHORIZED => Error::AuthenticationError(status, error),
+        reqwest::StatusCode::FORBIDDEN => Error::PermissionError(status, error),
+        reqwest::StatusCode::NOT_FOUND => Error::NotFoundError(status, error),
+        reqwest::StatusCode::CONFLICT => Error::ConflictError(status, error),
+        reqwest::StatusCode::TOO_MANY_REQUESTS => Error::RateLimitError(status, error),
+        reqwest::StatusCode::BAD_GATEWAY
+            | reqwest::StatusCode::SERVICE_UNAVAILABLE
+            | reqwest::StatusCode::GATEWAY_TIMEOUT => Error::UnavailableError(status, error),
+        s if s.is_client_error() => Error::ClientError(status, error),
+        _ => Error::ServerError(status, error),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::oauth2;
-    use crate::configuration::IdentityId;
+    use crate::transport::ScriptedTransport;
+    use serde_json::json;
 
-    use biscuit::jwk::JWK;
-    use std::fs::File;
-    use std::io::prelude::*;
-    use std::path::Path;
+    fn test_api() -> Api {
+        test_api_with(false)
+    }
 
+    fn test_api_with(dpop: bool) -> Api {
+        Api::new(test_configuration(dpop))
+    }
 
-    fn read_jwk(credentials: &Path) -> JWK<IdentityId> {
-        let mut file = File::open(credentials).expect("Credentials path should exist.");
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).expect("Should be able to read credentials file.");
-        serde_json::from_str(&contents).expect("Should be able to deserialise credentials file.")
+    /// An api that talks to `transport`, with tokens already granted.
+    fn scripted_api(transport: Arc<ScriptedTransport>) -> Api {
+        let api = Api::with_transport(test_configuration(false), transport);
+        for scope in &[PROFILE_SCOPE, CA_SCOPE] {
+            api.oauth2.state.lock().unwrap().insert(vec![scope.to_string()], oauth2::AccessTokenState {
+                token: oauth2::AccessToken { value: "scripted".to_string() },
+                expires_at: std::time::SystemTime::now() + std::time::Duration::from_secs(3600),
+            });
+        }
+        api
     }
 
-    fn test_api() -> Api {
+    fn test_configuration(dpop: bool) -> Configuration {
         let server = std::env::var("SERVER").unwrap_or("http://localhost:8000".to_string());
-        let jwk = read_jwk(Path::new("test/data/credentials.json"));
-        let oauth2 = oauth2::Configuration {
-            key: oauth2::Configuration::build_secret(&jwk).expect("Should be able to build signing secret"),
-            endpoint: format!("{}/oauth/token", server),
-            issuer: "me".to_string(),
-            audience: "mock".to_string(),
-            scopes: vec!["scope".to_string()],
-        };
-        let configuration = Configuration {
-            endpoint: server,
-            jwk,
-            oauth2,
-        };
-        Api::new(configuration)
+        let mut configuration = Configuration::for_tests(&server);
+        configuration.oauth2.dpop = Some(dpop);
+        configuration.oauth2.retry = retry::Policy { retries: 2, base: std::time::Duration::from_millis(10), max: std::time::Duration::from_secs(1) };
+        configuration
+    }
+
+    #[test]
+    fn test_api_is_shareable() {
+        fn shareable<A: Send + Sync>() {}
+        shareable::<Api>();
     }
 
     #[test]
     fn test_whoami() {
-        let mut api = test_api();
+        let transport = Arc::new(ScriptedTransport::new()
+            .respond_json(reqwest::Method::GET, "/userinfo", reqwest::StatusCode::OK, &json!({ "sub": "1" })));
+        let api = scripted_api(transport.clone());
         let userinfo = api.whoami().expect("Should be able to make userinfo call.");
         assert_eq!(userinfo, UserInfo { user_id: "1".to_string() } );
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers.get(header::AUTHORIZATION).expect("Request should be authorized."), "Bearer scripted");
+    }
+
+    #[test]
+    fn test_whoami_mock() {
+        let api = test_api();
+        let userinfo = api.whoami().expect("Should be able to make userinfo call.");
+        assert_eq!(userinfo, UserInfo { user_id: "1".to_string() } );
+    }
+
+    #[test]
+    fn test_whoami_dpop() {
+        let api = test_api_with(true);
+        let userinfo = api.whoami().expect("Should be able to make userinfo call with DPoP.");
+        assert_eq!(userinfo, UserInfo { user_id: "1".to_string() } );
+        assert!(api.oauth2.grant().expect("Grant should succeed.").value.starts_with("mock-dpop-"));
+    }
+
+    #[test]
+    fn test_operation_scopes() {
+        let api = test_api();
+        api.whoami().expect("Should be able to make userinfo call.");
+        assert!(api.oauth2.local_for(&["profile".to_string()]).is_some());
+        assert!(api.oauth2.local_for(&["ca".to_string()]).is_none());
+    }
+
+    #[test]
+    fn test_url() {
+        let api = test_api();
+        let url = api.url(&["environment", "public-keys", "a b/c?d"]).expect("Url should build.");
+        assert!(url.as_str().ends_with("/environment/public-keys/a%20b%2Fc%3Fd"));
+    }
+
+    #[test]
+    fn test_status_mapping() {
+        let api = test_api();
+        let status = |code: &str| api.get(&["status", code], &[PROFILE_SCOPE]);
+        assert!(status("200").is_ok());
+        assert!(match status("400") { Err(Error::ClientError(_, ref e)) => e.error() == "status-400", _ => false });
+        assert!(match status("401") { Err(Error::AuthenticationError(_, _)) => true, _ => false });
+        assert!(match status("403") { Err(Error::PermissionError(_, _)) => true, _ => false });
+        assert!(match status("404") { Err(Error::NotFoundError(_, _)) => true, _ => false });
+        assert!(match status("409") { Err(Error::ConflictError(_, _)) => true, _ => false });
+        assert!(match status("422") { Err(Error::ClientError(_, _)) => true, _ => false });
+        assert!(match status("429") { Err(Error::RateLimitError(_, _)) => true, _ => false });
+        assert!(match status("500") { Err(Error::ServerError(_, _)) => true, _ => false });
+        assert!(match status("503") { Err(Error::UnavailableError(_, _)) => true, _ => false });
+        // Proxies reply with plain text bodies.
+        assert!(match status("502") { Err(Error::UnavailableError(_, ref e)) => e.error() == "Bad Gateway", _ => false });
+    }
+
+    #[test]
+    fn test_retry() {
+        let api = test_api();
+        let id = format!("{}-{:?}", std::process::id(), std::time::SystemTime::now());
+        let userinfo: UserInfo = api.get(&["flaky", &id], &[PROFILE_SCOPE])
+            .expect("Retry should succeed.")
+            .json()
+            .expect("Response should parse.");
+        assert_eq!(userinfo, UserInfo { user_id: "1".to_string() } );
     }
 
     #[test]
     fn test_keys() {
+        let environment = Environment { name: "mock env".to_string() };
+        let transport = Arc::new(ScriptedTransport::new()
+            .respond_json(reqwest::Method::GET, "/environment/public-keys/mock%20env", reqwest::StatusCode::OK, &json!({
+                "public-keys": ["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC5 ca"],
+            })));
+        let api = scripted_api(transport.clone());
+        let keys = api.keys(&environment).expect("Should be able to make keys call.");
+        assert_eq!(keys.keys, vec!["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC5 ca".to_string()]);
+        assert!(transport.is_done());
+    }
+
+    #[test]
+    fn test_keys_retried() {
         let environment = Environment { name: "mock".to_string() };
-        let mut api = test_api();
+        let transport = Arc::new(ScriptedTransport::new()
+            .respond_json(reqwest::Method::GET, "/environment/public-keys/mock", reqwest::StatusCode::SERVICE_UNAVAILABLE, &json!({ "error": "unavailable" }))
+            .respond_json(reqwest::Method::GET, "/environment/public-keys/mock", reqwest::StatusCode::OK, &json!({ "public-keys": [] })));
+        let api = scripted_api(transport.clone());
         let keys = api.keys(&environment).expect("Should be able to make keys call.");
-        assert!(keys.keys.len() > 0);
+        assert!(keys.keys.is_empty());
+        assert_eq!(transport.requests().len(), 2);
     }
 
     #[test]
@@ -235,9 +449,54 @@ mod tests {
         let public_key = PublicKey { encoded: "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDI6z6dBtqnv2F0kqD8gnRMPkAoOdNpaa5qnx3UyXM8RApmBY180RKTSLzTRcrFFYxDfHLOFWw/V0JM4bLwNaHhhuYGllYqb2qHlVs7KgoytBGy//xtRMemkX2BY5UwD8iqw+5a45xqoddL8hTRk77ploFa7ItgTVVPD30l3hZHWWQr2/eINI9G41nLfQZkOYjkNf1s8DJsHI8FunKgp8lwGMUZaAq9mnYpVHBQX6LSjZiBUN9pIkoDO5+08AN6RIUIgJ9Q0T0AGLRcMQKTx1fkeV7wkreJF2TmBVUE0ZOIDQEOOis1+YigT4JAqrDI0+OYGzEGu2tHFRemjs3uvQLb test".to_string() };
         let principals = vec![Principal { name: "root".to_string() }];
         let host = Some(HostName { host: "host".to_string() });
-        let mut api = test_api();
-        let certificate = api.issue(&environment, &public_key, &principals, &host).expect("Should be able to make keys call.");
-        assert!(certificate.encoded.len() > 0);
+        let transport = Arc::new(ScriptedTransport::new()
+            .respond_json(reqwest::Method::POST, "/issue", reqwest::StatusCode::OK, &json!({ "certificate": "ssh-rsa-cert-v01@openssh.com AAAA test-cert" })));
+        let api = scripted_api(transport.clone());
+        let certificate = api.issue(&environment, &public_key, &principals, &host).expect("Should be able to make issue call.");
+        assert_eq!(certificate.encoded, "ssh-rsa-cert-v01@openssh.com AAAA test-cert");
+        let request = &transport.requests()[0];
+        assert!(request.headers.contains_key("Idempotency-Key"));
+        let body: Value = serde_json::from_slice(request.body.as_ref().expect("Request should have a body.")).expect("Body should be JSON.");
+        assert_eq!(body["principals"], json!(["root"]));
+        assert_eq!(body["host-name"], json!("host"));
+        assert!(body.get("valid-for").is_none());
+    }
+
+    #[test]
+    fn test_issue_with() {
+        let environment = Environment { name: "mock".to_string() };
+        let public_key = PublicKey { encoded: "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJHfN6DsdDo8sKxVhPH6Adq5U6oM1GuvDQRa4ozQM10C test".to_string() };
+        let transport = Arc::new(ScriptedTransport::new()
+            .respond_json(reqwest::Method::POST, "/issue", reqwest::StatusCode::OK, &json!({ "certificate": "ssh-ed25519-cert-v01@openssh.com AAAA test-cert" })));
+        let api = scripted_api(transport.clone());
+        let request = IssueRequest::new(&environment, &public_key)
+            .principals(&[Principal { name: "deploy".to_string() }])
+            .valid_for(std::time::Duration::from_secs(15 * 60))
+            .extensions(&[])
+            .force_command("/usr/local/bin/deploy")
+            .key_id("deploy-1");
+        api.issue_with(&request).expect("Should be able to make issue call.");
+        let body: Value = serde_json::from_slice(transport.requests()[0].body.as_ref().expect("Request should have a body.")).expect("Body should be JSON.");
+        assert_eq!(body["valid-for"], json!(900));
+        assert_eq!(body["extensions"], json!([]));
+        assert_eq!(body["critical-options"], json!({ "force-command": "/usr/local/bin/deploy" }));
+        assert_eq!(body["key-id"], json!("deploy-1"));
+    }
+
+    #[test]
+    fn test_issue_options() {
+        let api = test_api();
+        let environment = Environment { name: "mock".to_string() };
+        let public_key = PublicKey { encoded: "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJHfN6DsdDo8sKxVhPH6Adq5U6oM1GuvDQRa4ozQM10C test".to_string() };
+        let request = IssueRequest::new(&environment, &public_key)
+            .valid_for(std::time::Duration::from_secs(15 * 60))
+            .extensions(&["permit-pty"])
+            .source_address("10.0.0.0/8");
+        assert!(api.issue_with(&request).is_ok());
+        let too_long = request.clone().valid_for(std::time::Duration::from_secs(48 * 60 * 60));
+        assert!(match api.issue_with(&too_long) { Err(Error::ClientError(_, _)) => true, _ => false });
+        let unknown = request.clone().critical_option("verify-required", "");
+        assert!(match api.issue_with(&unknown) { Err(Error::ClientError(_, _)) => true, _ => false });
     }
 
 }
@@ -0,0 +1,227 @@
+use crate::api::{self, Error, CA_SCOPE, PROFILE_SCOPE};
+use crate::configuration::Configuration;
+use crate::data::{
+    AuthorityPublicKeys,
+    Certificate,
+    Environment,
+    HostName,
+    IssueRequest,
+    PublicKey,
+    Principal,
+    UserInfo,
+};
+use crate::oauth2::{self, AccessToken};
+use crate::oauth2::async_store::{AsyncStore, Response};
+use crate::retry::{self, Attempt};
+
+use crate::transport::{self, Request};
+
+use futures::future::{self, Either, Future, Loop};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::r#async::Client;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::timer::Delay;
+
+/// A smith API client for tokio based programs, with the same
+/// operations as `Api`, requests are retried and a rejected token is
+/// renewed in the same way. Tokens are always bearer tokens.
+#[derive(Clone)]
+pub struct AsyncApi {
+    pub configuration: Configuration,
+    pub oauth2: Arc<AsyncStore>,
+    pub client: Client,
+}
+
+impl AsyncApi {
+    /// Create a client, DPoP isn't supported so configurations that
+    /// enable it are refused rather than silently using bearer tokens,
+    /// when left to discovery bearer tokens are used.
+    pub fn new(configuration: Configuration) -> Result<AsyncApi, Error> {
+        if configuration.oauth2.dpop == Some(true) {
+            return Err(Error::UnsupportedDpopError);
+        }
+        let client = configuration.http.async_client().map_err(|e| Error::HttpError(e))?;
+        let oauth2 = Arc::new(AsyncStore::new(client.clone(), configuration.client.clone(), configuration.oauth2.clone()));
+        Ok(AsyncApi { configuration, oauth2, client })
+    }
+
+    fn scopes(&self, scopes: &[&str]) -> Vec<String> {
+        self.configuration.scopes.clone().unwrap_or_else(|| scopes.iter().map(|scope| scope.to_string()).collect())
+    }
+
+    /// Make an authorized request with a token for `scopes`, only
+    /// successful responses are returned. Requests that fail
+    /// transiently are retried, POSTs carry an `Idempotency-Key` as
+    /// they do with `Api`.
+    pub fn execute(&self, method: reqwest::Method, path: &[&str], scopes: &[&str], body: Option<Value>) -> Response<transport::Response, Error> {
+        let url = match api::url(&self.configuration.endpoint, path) {
+            Ok(url) => url,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let idempotency_key = match method {
+            reqwest::Method::POST => match oauth2::random(16) {
+                Ok(key) => Some(key),
+                Err(e) => return Box::new(future::err(Error::GrantError(e))),
+            },
+            _ => None,
+        };
+        let api = self.clone();
+        let scopes = self.scopes(scopes);
+        let response = future::loop_fn(0, move |attempt| {
+            let retry = api.configuration.oauth2.retry.clone();
+            api.authorized(&method, &url, &scopes, &body, &idempotency_key)
+                .map_err(retryable)
+                .and_then(|response| {
+                    let retry_after = retry::retry_after(&response.headers);
+                    api::check(response).map_err(move |e| match e {
+                        Error::RateLimitError(_, _) | Error::UnavailableError(_, _) => Attempt::Retry(e, retry_after),
+                        e => Attempt::Fail(e),
+                    })
+                })
+                .then(move |result| match result {
+                    Ok(response) => Either::A(future::ok(Loop::Break(response))),
+                    Err(Attempt::Fail(e)) => Either::A(future::err(e)),
+                    Err(Attempt::Retry(e, retry_after)) => match retry.delay(attempt, retry_after) {
+                        // A failed timer only means retrying sooner.
+                        Some(delay) => Either::B(Delay::new(Instant::now() + delay).then(move |_| -> Result<_, Error> { Ok(Loop::Continue(attempt + 1)) })),
+                        None => Either::A(future::err(e)),
+                    },
+                })
+        });
+        Box::new(response)
+    }
+
+    /// Send the request, a rejected token is renewed and the request
+    /// sent once more.
+    fn authorized(&self, method: &reqwest::Method, url: &reqwest::Url, scopes: &[String], body: &Option<Value>, idempotency_key: &Option<String>) -> Response<transport::Response, Error> {
+        let api = self.clone();
+        let (method, url, scopes, body, idempotency_key) = (method.clone(), url.clone(), scopes.to_vec(), body.clone(), idempotency_key.clone());
+        let response = self.oauth2
+            .grant_for(&scopes)
+            .map_err(|e| Error::GrantError(e))
+            .and_then(move |token| {
+                api.send(&method, &url, &body, &idempotency_key, &token).and_then(move |response| {
+                    if response.status != reqwest::StatusCode::UNAUTHORIZED {
+                        return Either::A(future::ok(response));
+                    }
+                    let renewed = api.oauth2
+                        .renew_for(&scopes)
+                        .map_err(|e| Error::GrantError(e))
+                        .and_then(move |token| api.send(&method, &url, &body, &idempotency_key, &token));
+                    Either::B(renewed)
+                })
+            });
+        Box::new(response)
+    }
+
+    fn send(&self, method: &reqwest::Method, url: &reqwest::Url, body: &Option<Value>, idempotency_key: &Option<String>, token: &AccessToken) -> Response<transport::Response, Error> {
+        let headers = match headers(token, idempotency_key, body.is_some()) {
+            Ok(headers) => headers,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let request = Request {
+            method: method.clone(),
+            url: url.clone(),
+            headers: headers,
+            body: body.as_ref().map(|body| body.to_string().into_bytes()),
+        };
+        match self.configuration.oauth2.pins {
+            Some(ref pins) => Box::new(pins.send_async(request).map_err(|e| Error::PinningError(e))),
+            None => Box::new(transport::send_async_with(&self.client, request).map_err(|e| Error::RequestError(e))),
+        }
+    }
+
+    pub fn whoami(&self) -> Response<UserInfo, Error> {
+        json(self.execute(reqwest::Method::GET, &["userinfo"], &[PROFILE_SCOPE], None))
+    }
+
+    pub fn keys(&self, environment: &Environment) -> Response<AuthorityPublicKeys, Error> {
+        json(self.execute(reqwest::Method::GET, &["environment", "public-keys", &environment.name], &[CA_SCOPE], None))
+    }
+
+    pub fn issue(&self, environment: &Environment, public_key: &PublicKey, principals: &[Principal], host: &Option<HostName>) -> Response<Certificate, Error> {
+        self.issue_with(&IssueRequest::new(environment, public_key).principals(principals).host(host))
+    }
+
+    pub fn issue_with(&self, request: &IssueRequest) -> Response<Certificate, Error> {
+        json(self.execute(reqwest::Method::POST, &["issue"], &[CA_SCOPE], Some(request.to_json())))
+    }
+}
+
+fn headers(token: &AccessToken, idempotency_key: &Option<String>, has_body: bool) -> Result<HeaderMap, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+    headers.insert(header::AUTHORIZATION, api::header_value("Authorization", &format!("Bearer {}", token.value))?);
+    if let Some(idempotency_key) = idempotency_key {
+        headers.insert("Idempotency-Key", api::header_value("Idempotency-Key", idempotency_key)?);
+    }
+    if has_body {
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    }
+    Ok(headers)
+}
+
+fn json<T: DeserializeOwned + Send + 'static>(response: Response<transport::Response, Error>) -> Response<T, Error> {
+    Box::new(response.and_then(|response| response.json()))
+}
+
+/// Whether a failed request is worth retrying, unlike `Api` grants
+/// aren't retried by the store so they are retried here.
+fn retryable(error: Error) -> Attempt<Error> {
+    match error {
+        Error::GrantError(e) => match oauth2::attempt(e) {
+            Attempt::Retry(e, retry_after) => Attempt::Retry(Error::GrantError(e), retry_after),
+            Attempt::Fail(e) => Attempt::Fail(Error::GrantError(e)),
+        },
+        e => api::attempt(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry;
+
+    use tokio::runtime::Runtime;
+
+    fn test_api() -> AsyncApi {
+        let server = std::env::var("SERVER").unwrap_or("http://localhost:8000".to_string());
+        let mut configuration = Configuration::for_tests(&server);
+        configuration.oauth2.retry = retry::Policy::disabled();
+        AsyncApi::new(configuration).expect("Should be able to create api.")
+    }
+
+    #[test]
+    fn test_whoami() {
+        let api = test_api();
+        let userinfo = Runtime::new().expect("Runtime should start.").block_on(api.whoami()).expect("Should be able to make userinfo call.");
+        assert_eq!(userinfo, UserInfo { user_id: "1".to_string() } );
+        assert!(api.oauth2.local_for(&["profile".to_string()]).is_some());
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut api = test_api();
+        api.configuration.oauth2.retry = retry::Policy { retries: 2, base: std::time::Duration::from_millis(10), max: std::time::Duration::from_secs(1) };
+        let id = format!("async-{}-{:?}", std::process::id(), std::time::SystemTime::now());
+        let result = Runtime::new().expect("Runtime should start.").block_on(api.execute(reqwest::Method::GET, &["flaky", &id], &[PROFILE_SCOPE], None));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_dpop_refused() {
+        let mut configuration = test_api().configuration;
+        configuration.oauth2.dpop = Some(true);
+        assert!(match AsyncApi::new(configuration) { Err(Error::UnsupportedDpopError) => true, _ => false });
+    }
+
+    #[test]
+    fn test_not_found() {
+        let api = test_api();
+        let result = Runtime::new().expect("Runtime should start.").block_on(api.execute(reqwest::Method::GET, &["status", "404"], &[PROFILE_SCOPE], None));
+        assert!(match result { Err(Error::NotFoundError(_, _)) => true, _ => false });
+    }
+}
@@ -0,0 +1,244 @@
+use crate::data::{AuthorityPublicKeys, Environment};
+use crate::file;
+use crate::oauth2::cache;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac};
+use std::fmt;
+use std::fs::{DirBuilder, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The last CA public keys fetched for each environment, cached on
+/// disk in `directory` so hosts can still be provisioned when smith is
+/// unreachable. Entries carry an HMAC, keyed with a random secret kept
+/// in the directory, so a corrupted cache, or one changed by anyone
+/// who can't read the secret, is never trusted. The directory must be
+/// private to the user, root for sshd's keys, as anyone who can read
+/// the secret could forge entries.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AuthorityCache {
+    pub directory: PathBuf,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct CachedKeys {
+    environment: String,
+    fetched_at: u64,
+    #[serde(rename = "public-keys")]
+    keys: Vec<String>,
+    checksum: String,
+}
+
+/// Keys from the cache, and how long ago they were fetched.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CachedAuthority {
+    pub keys: AuthorityPublicKeys,
+    pub age: Duration,
+}
+
+#[derive(Debug)]
+pub enum AuthorityCacheError {
+    IoError(std::io::Error),
+    JsonError(serde_json::Error),
+    MissingError(String),
+    ChecksumError(PathBuf),
+    StaleError(String, Duration),
+    InsecureError(PathBuf),
+}
+
+impl fmt::Display for AuthorityCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthorityCacheError::IoError(_) =>
+              write!(f, "CA key cache could not be read or written, check its permissions."),
+            AuthorityCacheError::JsonError(_) =>
+              write!(f, "CA key cache is not valid JSON."),
+            AuthorityCacheError::MissingError(environment) =>
+              write!(f, "No CA keys are cached for environment '{}'.", environment),
+            AuthorityCacheError::ChecksumError(path) =>
+              write!(f, "Cached CA keys failed their checksum and will not be used, tried: {:?}", path),
+            AuthorityCacheError::StaleError(environment, age) =>
+              write!(f, "Cached CA keys for environment '{}' are {} hours old, older than allowed by --max-stale.", environment, age.as_secs() / 3600),
+            AuthorityCacheError::InsecureError(path) =>
+              write!(f, "CA key cache must be owned by this user and not accessible to others, it will not be used, tried: {:?}", path),
+        }
+    }
+}
+
+impl From<std::io::Error> for AuthorityCacheError {
+    fn from(e: std::io::Error) -> AuthorityCacheError {
+        AuthorityCacheError::IoError(e)
+    }
+}
+
+impl AuthorityCache {
+    pub fn new(directory: PathBuf) -> AuthorityCache {
+        AuthorityCache { directory }
+    }
+
+    pub fn path(&self, environment: &Environment) -> PathBuf {
+        self.directory.join(format!("ca-keys-{}.json", cache::hex_digest(&environment.name)))
+    }
+
+    pub fn store(&self, environment: &Environment, keys: &AuthorityPublicKeys) -> Result<(), AuthorityCacheError> {
+        let fetched_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs();
+        self.store_at(environment, keys, fetched_at)
+    }
+
+    fn store_at(&self, environment: &Environment, keys: &AuthorityPublicKeys, fetched_at: u64) -> Result<(), AuthorityCacheError> {
+        DirBuilder::new().recursive(true).mode(0o700).create(&self.directory)?;
+        if std::fs::metadata(&self.directory)?.uid() == euid() {
+            std::fs::set_permissions(&self.directory, Permissions::from_mode(0o700))?;
+        }
+        self.check_directory()?;
+        // Entries signed with a lost or damaged secret can't be
+        // verified anyway, so it is replaced.
+        let key = match self.key()? {
+            Some(key) => key,
+            None => self.create_key()?,
+        };
+        let checksum = checksum(&key, &environment.name, fetched_at, &keys.keys);
+        let cached = CachedKeys { environment: environment.name.clone(), fetched_at, keys: keys.keys.clone(), checksum };
+        let contents = serde_json::to_vec(&cached).map_err(|e| AuthorityCacheError::JsonError(e))?;
+        file::write_atomic(&self.path(environment), &contents, &file::Options { mode: Some(0o644), ..file::Options::default() })?;
+        Ok(())
+    }
+
+    /// The cached keys for `environment`, unless older than `max_stale`.
+    pub fn load(&self, environment: &Environment, max_stale: Option<Duration>) -> Result<CachedAuthority, AuthorityCacheError> {
+        let path = self.path(environment);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Err(AuthorityCacheError::MissingError(environment.name.clone())),
+            Err(e) => return Err(AuthorityCacheError::IoError(e)),
+        };
+        self.check_directory()?;
+        let cached: CachedKeys = serde_json::from_str(&contents).map_err(|e| AuthorityCacheError::JsonError(e))?;
+        let key = self.key()?.ok_or_else(|| AuthorityCacheError::ChecksumError(path.clone()))?;
+        if cached.environment != environment.name || !verify(&key, &cached) {
+            return Err(AuthorityCacheError::ChecksumError(path));
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs();
+        let age = Duration::from_secs(now.saturating_sub(cached.fetched_at));
+        if max_stale.map(|max_stale| age > max_stale).unwrap_or(false) {
+            return Err(AuthorityCacheError::StaleError(environment.name.clone(), age));
+        }
+        Ok(CachedAuthority { keys: AuthorityPublicKeys { keys: cached.keys }, age })
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.directory.join("ca-keys.secret")
+    }
+
+    /// The secret entries are signed with, none if it is missing or
+    /// damaged.
+    fn key(&self) -> Result<Option<hmac::SigningKey>, AuthorityCacheError> {
+        match std::fs::read(self.key_path()) {
+            Ok(ref secret) if secret.len() == SECRET_LENGTH => Ok(Some(hmac::SigningKey::new(&digest::SHA256, secret))),
+            Ok(_) => Ok(None),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(AuthorityCacheError::IoError(e)),
+        }
+    }
+
+    fn create_key(&self) -> Result<hmac::SigningKey, AuthorityCacheError> {
+        let mut secret = [0u8; SECRET_LENGTH];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .map_err(|_e| std::io::Error::new(ErrorKind::Other, "could not generate a CA key cache secret"))?;
+        file::write_atomic(&self.key_path(), &secret, &file::Options::private())?;
+        Ok(hmac::SigningKey::new(&digest::SHA256, &secret))
+    }
+
+    /// The directory must belong to this user and be private to it,
+    /// or another user could read the secret and replace the keys.
+    fn check_directory(&self) -> Result<(), AuthorityCacheError> {
+        let metadata = std::fs::metadata(&self.directory)?;
+        if metadata.uid() != euid() || metadata.mode() & 0o077 != 0 {
+            return Err(AuthorityCacheError::InsecureError(self.directory.clone()));
+        }
+        Ok(())
+    }
+}
+
+const SECRET_LENGTH: usize = 32;
+
+fn euid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+fn signed_contents(environment: &str, fetched_at: u64, keys: &[String]) -> String {
+    format!("{}\n{}\n{}", environment, fetched_at, keys.join("\n"))
+}
+
+fn checksum(key: &hmac::SigningKey, environment: &str, fetched_at: u64, keys: &[String]) -> String {
+    hmac::sign(key, signed_contents(environment, fetched_at, keys).as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn verify(key: &hmac::SigningKey, cached: &CachedKeys) -> bool {
+    let signature = match unhex(&cached.checksum) {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let contents = signed_contents(&cached.environment, cached.fetched_at, &cached.keys);
+    hmac::verify_with_own_key(key, contents.as_bytes(), &signature).is_ok()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let directory = std::env::temp_dir().join(format!("smith-authority-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let cache = AuthorityCache::new(directory.clone());
+        let environment = Environment { name: "mock".to_string() };
+        let keys = AuthorityPublicKeys { keys: vec!["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEgIpsX3r/ow5SALwmH85BTG2rkuWYxCPrhsdsrT2NcM ca".to_string()] };
+        assert!(match cache.load(&environment, None) { Err(AuthorityCacheError::MissingError(_)) => true, _ => false });
+
+        cache.store(&environment, &keys).expect("Store should succeed.");
+        assert_eq!(cache.load(&environment, Some(Duration::from_secs(60))).expect("Cache should be used.").keys, keys);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Clock should be valid.").as_secs();
+        cache.store_at(&environment, &keys, now - 7200).expect("Store should succeed.");
+        assert!(match cache.load(&environment, Some(Duration::from_secs(3600))) { Err(AuthorityCacheError::StaleError(_, _)) => true, _ => false });
+        assert!(cache.load(&environment, None).is_ok());
+
+        let contents = std::fs::read_to_string(cache.path(&environment)).expect("Cache should be readable.");
+        std::fs::write(cache.path(&environment), contents.replace("AAAAC3", "AAAAB3")).expect("Cache should be writable.");
+        assert!(match cache.load(&environment, None) { Err(AuthorityCacheError::ChecksumError(_)) => true, _ => false });
+
+        // An unkeyed digest, as anyone could compute, isn't accepted.
+        let mut forged: CachedKeys = serde_json::from_str(&contents).expect("Cache should be json.");
+        forged.checksum = cache::hex_digest(&signed_contents(&forged.environment, forged.fetched_at, &forged.keys));
+        std::fs::write(cache.path(&environment), serde_json::to_vec(&forged).expect("Cache should serialise.")).expect("Cache should be writable.");
+        assert!(match cache.load(&environment, None) { Err(AuthorityCacheError::ChecksumError(_)) => true, _ => false });
+
+        // Nor is an entry once the secret is replaced.
+        cache.store(&environment, &keys).expect("Store should succeed.");
+        std::fs::remove_file(cache.key_path()).expect("Secret should be removed.");
+        assert!(match cache.load(&environment, None) { Err(AuthorityCacheError::ChecksumError(_)) => true, _ => false });
+        cache.store(&environment, &keys).expect("Store should replace the secret.");
+        assert!(cache.load(&environment, None).is_ok());
+
+        std::fs::set_permissions(&directory, Permissions::from_mode(0o755)).expect("Mode should be set.");
+        assert!(match cache.load(&environment, None) { Err(AuthorityCacheError::InsecureError(_)) => true, _ => false });
+
+        std::fs::remove_dir_all(&directory).expect("Directory should be removed.");
+    }
+}
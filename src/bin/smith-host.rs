@@ -1,17 +1,21 @@
 extern crate smith_ssh;
 
-use clap::{App, AppSettings, Arg};
-use smith_ssh::data::{AuthorityPublicKeys, Environment};
-use smith_ssh::api::Api;
-use smith_ssh::configuration::Configuration;
-use std::fs::File;
-use std::io::Write;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use smith_ssh::data::{self, AuthorityPublicKeys, Certificate, Environment, HostName, IssueRequest, Principal, PublicKey};
+use smith_ssh::api::{self, Api};
+use smith_ssh::configuration::{self, Configuration};
+use smith_ssh::file::{self, Options};
+use smith_ssh::keys;
+use smith_ssh::retry;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 fn main() {
     let matches = App::new("smith-host")
 	.version(&smith_ssh::version::smith_version()[..])
 	.about("Fetch certificate-authority public keys for smith managed hosts.")
 	.setting(AppSettings::ArgRequiredElseHelp)
+	.setting(AppSettings::SubcommandsNegateReqs)
 	.arg(Arg::with_name("ENVIRONMENT")
 	     .short("e")
 	     .long("environment")
@@ -22,44 +26,185 @@ fn main() {
 	.arg(Arg::with_name("FILE")
              .help("Output path for certificate authority public keys file.")
 	     .required(false))
+	.arg(Arg::with_name("BACKUP")
+	     .long("backup")
+	     .help("Keep the file being replaced as FILE.bak.")
+	     .required(false))
+	.arg(Arg::with_name("OWNER")
+	     .long("owner")
+	     .help("The user, and optionally group, to own FILE, e.g. root:ssh.")
+	     .value_name("USER[:GROUP]")
+	     .validator(|owner| file::parse_owner(&owner).map(|_| ()))
+	     .required(false))
+	.arg(Arg::with_name("MAX_STALE")
+	     .long("max-stale")
+	     .help("The oldest cached keys to fall back to if smith is unreachable.")
+	     .value_name("DURATION")
+	     .default_value("7d")
+	     .validator(|duration| data::parse_duration(&duration).map(|_| ()))
+	     .required(false))
+	.subcommand(SubCommand::with_name("issue")
+	     .about("Issue host certificates for existing host keys, written next to each key.")
+	     .arg(Arg::with_name("ENVIRONMENT")
+		  .short("e")
+		  .long("environment")
+		  .help("The environment to issue host certificates in.")
+		  .env("SMITH_ENVIRONMENT")
+		  .value_name("ENVIRONMENT")
+		  .required(true))
+	     .arg(Arg::with_name("HOST_NAME")
+		  .long("host-name")
+		  .help("A name clients connect to this host by, repeat for aliases.")
+		  .value_name("NAME")
+		  .multiple(true)
+		  .number_of_values(1)
+		  .required(true))
+	     .arg(Arg::with_name("KEY")
+		  .help("Host public keys to certify, e.g. /etc/ssh/ssh_host_ed25519_key.pub.")
+		  .multiple(true)
+		  .required(true)))
+	.subcommand(SubCommand::with_name("sync")
+	     .about("Update a certificate-authority public keys file if the keys have changed, exits 0 if unchanged, 2 if changed and 1 on error.")
+	     .arg(Arg::with_name("ENVIRONMENT")
+		  .short("e")
+		  .long("environment")
+		  .help("The environment to fetch public keys for.")
+		  .env("SMITH_ENVIRONMENT")
+		  .value_name("ENVIRONMENT")
+		  .required(true))
+	     .arg(Arg::with_name("HOOK")
+		  .long("hook")
+		  .help("A shell command to run after the file is changed, e.g. 'systemctl reload sshd'.")
+		  .value_name("COMMAND")
+		  .required(false))
+	     .arg(Arg::with_name("BACKUP")
+		  .long("backup")
+		  .help("Keep the file being replaced as FILE.bak.")
+		  .required(false))
+	     .arg(Arg::with_name("OWNER")
+		  .long("owner")
+		  .help("The user, and optionally group, to own FILE, e.g. root:ssh.")
+		  .value_name("USER[:GROUP]")
+		  .validator(|owner| file::parse_owner(&owner).map(|_| ()))
+		  .required(false))
+	     .arg(Arg::with_name("MAX_STALE")
+		  .long("max-stale")
+		  .help("The oldest cached keys to fall back to if smith is unreachable.")
+		  .value_name("DURATION")
+		  .default_value("7d")
+		  .validator(|duration| data::parse_duration(&duration).map(|_| ()))
+		  .required(false))
+	     .arg(Arg::with_name("FILE")
+		  .help("Path of the certificate-authority public keys file.")
+		  .required(true)))
+	.subcommand(SubCommand::with_name("seed")
+	     .about("Cache certificate-authority public keys, e.g. when building an image, to fall back to if smith is unreachable.")
+	     .arg(Arg::with_name("ENVIRONMENT")
+		  .short("e")
+		  .long("environment")
+		  .help("The environment the keys are for.")
+		  .env("SMITH_ENVIRONMENT")
+		  .value_name("ENVIRONMENT")
+		  .required(true))
+	     .arg(Arg::with_name("FILE")
+		  .help("A certificate-authority public keys file to cache, fetched from smith if not given.")
+		  .required(false)))
+	.subcommand(SubCommand::with_name("renew")
+	     .about("Renew host certificates that are due, written next to each key.")
+	     .arg(Arg::with_name("ENVIRONMENT")
+		  .short("e")
+		  .long("environment")
+		  .help("The environment to issue host certificates in.")
+		  .env("SMITH_ENVIRONMENT")
+		  .value_name("ENVIRONMENT")
+		  .required(true))
+	     .arg(Arg::with_name("HOST_NAME")
+		  .long("host-name")
+		  .help("A name clients connect to this host by, repeat for aliases, the existing certificate's names by default.")
+		  .value_name("NAME")
+		  .multiple(true)
+		  .number_of_values(1)
+		  .required(false))
+	     .arg(Arg::with_name("RENEW_BEFORE")
+		  .long("renew-before")
+		  .help("Renew certificates this long before they expire, with a third of their validity left by default.")
+		  .value_name("DURATION")
+		  .validator(|duration| data::parse_duration(&duration).map(|_| ()))
+		  .required(false))
+	     .arg(Arg::with_name("WATCH")
+		  .long("watch")
+		  .help("Keep running, checking the certificates every interval.")
+		  .required(false))
+	     .arg(Arg::with_name("INTERVAL")
+		  .long("interval")
+		  .help("How often to check the certificates with --watch.")
+		  .value_name("DURATION")
+		  .default_value("1h")
+		  .validator(|duration| data::parse_duration(&duration).map(|_| ()))
+		  .required(false))
+	     .arg(Arg::with_name("HOOK")
+		  .long("hook")
+		  .help("A shell command to run after certificates are renewed, e.g. 'systemctl reload sshd'.")
+		  .value_name("COMMAND")
+		  .required(false))
+	     .arg(Arg::with_name("KEY")
+		  .help("Host public keys to certify, e.g. /etc/ssh/ssh_host_ed25519_key.pub.")
+		  .multiple(true)
+		  .required(true)))
 	.get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("issue") {
+        issue(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("sync") {
+        sync(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("seed") {
+        seed(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("renew") {
+        renew(matches);
+    }
+
     let environment = matches.value_of("ENVIRONMENT").unwrap_or_else(|| {
         eprintln!("Problem parsing arguments, no ENVIRONMENT specified.");
         std::process::exit(1);
     });
 
-    let file = matches.value_of("FILE");
+    let output = matches.value_of("FILE");
+    let options = options(&matches);
+    let max_stale = max_stale(&matches);
 
     if cfg!(feature = "cli-test") {
         println!("SMITH_CLI_ENVIRONMENT='{}'", environment);
-        if let Some(file) = file {
-            println!("SMITH_CLI_CA_OUTPUT='{}'", file);
+        if let Some(output) = output {
+            println!("SMITH_CLI_CA_OUTPUT='{}'", output);
+        }
+        println!("SMITH_CLI_BACKUP='{}'", options.backup);
+        if let Some((uid, gid)) = options.owner {
+            println!("SMITH_CLI_OWNER='{}:{}'", uid, gid);
         }
+        println!("SMITH_CLI_MAX_STALE='{}'", max_stale.as_secs());
         std::process::exit(0)
     }
 
-    let configuration = Configuration::from_env();
-    let mut api = Api::new(configuration);
-    match api.keys(&Environment { name: environment.to_string() } ) {
+    match authority_keys(&Environment { name: environment.to_string() }, max_stale) {
         Ok(AuthorityPublicKeys { keys }) => {
-            match file {
+            match output {
                 None => {
                     for key in keys.iter() {
                         println!("{}", key);
                     }
                 },
-                Some(file) => {
-                    let mut file = File::create(file).unwrap_or_else(|e| {
-                        eprintln!("Could not create file to write certificate-authority keys: {}", e);
+                Some(output) => {
+                    let contents = keys.iter().map(|key| format!("{}\n", key)).collect::<String>();
+                    file::write_atomic(Path::new(output), contents.as_bytes(), &options).unwrap_or_else(|e| {
+                        eprintln!("Could not write certificate-authority keys to specified file: {}", e);
                         std::process::exit(1);
                     });
-                    for key in keys.iter() {
-                        file.write_all(format!("{}\n", &key).as_bytes()).unwrap_or_else(|e| {
-                            eprintln!("Could not write key to specified file: {}", e);
-                            std::process::exit(1);
-                        });
-                    }
                 },
             }
         },
@@ -69,3 +214,366 @@ fn main() {
         },
     }
 }
+
+/// Issue a certificate for each host key, and print the sshd_config
+/// lines to use them.
+fn issue(matches: &ArgMatches) -> ! {
+    let environment = Environment { name: matches.value_of("ENVIRONMENT").unwrap_or_default().to_string() };
+    let names = matches.values_of("HOST_NAME").map(|names| names.collect::<Vec<&str>>()).unwrap_or_default();
+    let keys = matches.values_of("KEY").map(|keys| keys.map(PathBuf::from).collect::<Vec<_>>()).unwrap_or_default();
+
+    if cfg!(feature = "cli-test") {
+        println!("SMITH_CLI_SUBCOMMAND='issue'");
+        println!("SMITH_CLI_ENVIRONMENT='{}'", environment.name);
+        println!("SMITH_CLI_HOST_NAMES='{}'", names.join(","));
+        println!("SMITH_CLI_HOST_KEYS='{}'", keys.iter().map(|key| key.display().to_string()).collect::<Vec<_>>().join(" "));
+        std::process::exit(0)
+    }
+
+    let configuration = Configuration::from_env().with_scopes(&[api::CA_SCOPE]);
+    let api = Api::new(configuration);
+    let names = names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+    for key in keys.iter() {
+        let path = issue_for(&api, &environment, key, &names).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        println!("HostCertificate {}", path.display());
+    }
+    std::process::exit(0)
+}
+
+/// Exit codes for `sync`, so config management can tell whether
+/// anything changed.
+const SYNC_UNCHANGED: i32 = 0;
+const SYNC_ERROR: i32 = 1;
+const SYNC_CHANGED: i32 = 2;
+
+/// Write the CA keys to a file only if they have changed, reporting
+/// keys added and removed by fingerprint.
+fn sync(matches: &ArgMatches) -> ! {
+    let environment = Environment { name: matches.value_of("ENVIRONMENT").unwrap_or_default().to_string() };
+    let output = PathBuf::from(matches.value_of("FILE").unwrap_or_default());
+    let hook = matches.value_of("HOOK");
+    let options = options(matches);
+    let max_stale = max_stale(matches);
+
+    if cfg!(feature = "cli-test") {
+        println!("SMITH_CLI_SUBCOMMAND='sync'");
+        println!("SMITH_CLI_ENVIRONMENT='{}'", environment.name);
+        println!("SMITH_CLI_CA_OUTPUT='{}'", output.display());
+        println!("SMITH_CLI_BACKUP='{}'", options.backup);
+        if let Some((uid, gid)) = options.owner {
+            println!("SMITH_CLI_OWNER='{}:{}'", uid, gid);
+        }
+        println!("SMITH_CLI_MAX_STALE='{}'", max_stale.as_secs());
+        if let Some(hook) = hook {
+            println!("SMITH_CLI_HOOK='{}'", hook);
+        }
+        std::process::exit(0)
+    }
+
+    let AuthorityPublicKeys { keys } = authority_keys(&environment, max_stale).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(SYNC_ERROR)
+    });
+    let contents = keys.iter().map(|key| format!("{}\n", key)).collect::<String>();
+    let current = match std::fs::read_to_string(&output) {
+        Ok(current) => current,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            eprintln!("Could not read certificate-authority keys file {:?}: {}", output, e);
+            std::process::exit(SYNC_ERROR)
+        },
+    };
+    // A marker is kept while a hook is owed for the file, so a hook
+    // that fails, or never runs, is run again by the next sync even
+    // though the file is already up to date.
+    let pending = hook_pending_path(&output);
+    let run = |hook: &str| -> ! {
+        run_hook(hook).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(SYNC_ERROR)
+        });
+        if let Err(e) = std::fs::remove_file(&pending) {
+            eprintln!("Could not remove hook marker {:?}: {}", pending, e);
+        }
+        std::process::exit(SYNC_CHANGED)
+    };
+    if current == contents {
+        if let Some(hook) = hook.filter(|_| pending.exists()) {
+            run(hook)
+        }
+        std::process::exit(SYNC_UNCHANGED)
+    }
+
+    let fingerprints = |lines: Vec<&str>| lines.into_iter().filter_map(keys::fingerprint).collect::<Vec<_>>();
+    let before = fingerprints(current.lines().filter(|line| !line.trim().is_empty() && !line.starts_with('#')).collect());
+    let after = fingerprints(keys.iter().map(|key| &key[..]).collect());
+    for fingerprint in after.iter().filter(|fingerprint| !before.contains(*fingerprint)) {
+        println!("Added CA key {}", fingerprint);
+    }
+    for fingerprint in before.iter().filter(|fingerprint| !after.contains(*fingerprint)) {
+        println!("Removed CA key {}", fingerprint);
+    }
+    if let Some(hook) = hook {
+        file::write_atomic(&pending, format!("{}\n", hook).as_bytes(), &Options::private()).unwrap_or_else(|e| {
+            eprintln!("Could not write hook marker {:?}: {}", pending, e);
+            std::process::exit(SYNC_ERROR)
+        });
+    }
+    file::write_atomic(&output, contents.as_bytes(), &options).unwrap_or_else(|e| {
+        eprintln!("Could not write certificate-authority keys file {:?}: {}", output, e);
+        std::process::exit(SYNC_ERROR)
+    });
+    if let Some(hook) = hook {
+        run(hook)
+    }
+    std::process::exit(SYNC_CHANGED)
+}
+
+/// `smith_ca.pub` is marked as owing a hook by `.smith_ca.pub.hook-pending`.
+fn hook_pending_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!(".{}.hook-pending", name))
+}
+
+/// Cache CA keys from a file, or fetched from smith, so a host can be
+/// provisioned without reaching smith.
+fn seed(matches: &ArgMatches) -> ! {
+    let environment = Environment { name: matches.value_of("ENVIRONMENT").unwrap_or_default().to_string() };
+    let input = matches.value_of("FILE");
+
+    if cfg!(feature = "cli-test") {
+        println!("SMITH_CLI_SUBCOMMAND='seed'");
+        println!("SMITH_CLI_ENVIRONMENT='{}'", environment.name);
+        if let Some(input) = input {
+            println!("SMITH_CLI_CA_INPUT='{}'", input);
+        }
+        std::process::exit(0)
+    }
+
+    let keys = match input {
+        Some(input) => {
+            let contents = std::fs::read_to_string(input).unwrap_or_else(|e| {
+                eprintln!("Could not read certificate-authority keys file {:?}: {}", input, e);
+                std::process::exit(1);
+            });
+            let keys = contents
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>();
+            if let Some(invalid) = keys.iter().find(|key| keys::fingerprint(key).is_none()) {
+                eprintln!("Could not read certificate-authority keys file {:?}, '{}' is not a public key.", input, invalid);
+                std::process::exit(1);
+            }
+            AuthorityPublicKeys { keys }
+        },
+        None => {
+            let configuration = Configuration::from_env().with_scopes(&[api::CA_SCOPE]);
+            Api::new(configuration).keys(&environment).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            })
+        },
+    };
+    let cache = configuration::authority_cache_from_env();
+    cache.store(&environment, &keys).unwrap_or_else(|e| {
+        eprintln!("Could not cache certificate-authority keys in {:?}: {}", cache.directory, e);
+        std::process::exit(1);
+    });
+    eprintln!("Cached {} certificate-authority keys for {}.", keys.keys.len(), environment.name);
+    std::process::exit(0)
+}
+
+/// CA keys from smith, which are cached, falling back to the cache if
+/// smith can't be configured or reached.
+fn authority_keys(environment: &Environment, max_stale: Duration) -> Result<AuthorityPublicKeys, String> {
+    let cache = configuration::authority_cache_from_env();
+    let fetched = Configuration::try_from_env()
+        .map_err(|e| e.to_string())
+        .and_then(|configuration| Api::new(configuration.with_scopes(&[api::CA_SCOPE])).keys(environment).map_err(|e| e.to_string()));
+    match fetched {
+        Ok(keys) => {
+            if let Err(e) = cache.store(environment, &keys) {
+                eprintln!("Could not cache certificate-authority keys, they won't be available if smith is unreachable: {}", e);
+            }
+            Ok(keys)
+        },
+        Err(err) => match cache.load(environment, Some(max_stale)) {
+            Ok(cached) => {
+                eprintln!("WARNING: Could not fetch certificate-authority keys from smith: {}", err);
+                eprintln!("WARNING: Using keys cached {} hours ago, they may be out of date.", cached.age.as_secs() / 3600);
+                Ok(cached.keys)
+            },
+            Err(e) => Err(format!("{}\nThere are no cached keys to fall back to: {}", err, e)),
+        },
+    }
+}
+
+/// How to write FILE, from `--backup` and `--owner`.
+fn options(matches: &ArgMatches) -> Options {
+    let owner = matches.value_of("OWNER").map(|owner| file::parse_owner(owner).unwrap_or_else(|e| {
+        eprintln!("Problem parsing arguments, {}", e);
+        std::process::exit(1);
+    }));
+    Options { backup: matches.occurrences_of("BACKUP") > 0, owner, ..Options::default() }
+}
+
+fn max_stale(matches: &ArgMatches) -> Duration {
+    matches.value_of("MAX_STALE")
+        .map(|duration| data::parse_duration(duration).unwrap_or_else(|e| {
+            eprintln!("Problem parsing arguments, {}", e);
+            std::process::exit(1);
+        }))
+        .unwrap_or(Duration::from_secs(7 * 24 * 60 * 60))
+}
+
+/// Renew certificates that are due, once or, with `--watch`, forever.
+/// While watching, failures, including a failed hook, are reported and
+/// retried with backoff, and checks are jittered so a fleet of hosts
+/// doesn't renew together.
+fn renew(matches: &ArgMatches) -> ! {
+    let environment = Environment { name: matches.value_of("ENVIRONMENT").unwrap_or_default().to_string() };
+    let names = matches.values_of("HOST_NAME").map(|names| names.map(|name| name.to_string()).collect::<Vec<_>>()).unwrap_or_default();
+    let keys = matches.values_of("KEY").map(|keys| keys.map(PathBuf::from).collect::<Vec<_>>()).unwrap_or_default();
+    let duration = |name| matches.value_of(name).map(|duration| data::parse_duration(duration).unwrap_or_else(|e| {
+        eprintln!("Problem parsing arguments, {}", e);
+        std::process::exit(1);
+    }));
+    let renew_before = duration("RENEW_BEFORE");
+    let interval = duration("INTERVAL").unwrap_or(Duration::from_secs(60 * 60));
+    let watch = matches.occurrences_of("WATCH") > 0;
+    let hook = matches.value_of("HOOK");
+
+    if cfg!(feature = "cli-test") {
+        println!("SMITH_CLI_SUBCOMMAND='renew'");
+        println!("SMITH_CLI_ENVIRONMENT='{}'", environment.name);
+        println!("SMITH_CLI_HOST_NAMES='{}'", names.join(","));
+        println!("SMITH_CLI_HOST_KEYS='{}'", keys.iter().map(|key| key.display().to_string()).collect::<Vec<_>>().join(" "));
+        if let Some(renew_before) = renew_before {
+            println!("SMITH_CLI_RENEW_BEFORE='{}'", renew_before.as_secs());
+        }
+        println!("SMITH_CLI_INTERVAL='{}'", interval.as_secs());
+        println!("SMITH_CLI_WATCH='{}'", watch);
+        if let Some(hook) = hook {
+            println!("SMITH_CLI_HOOK='{}'", hook);
+        }
+        std::process::exit(0)
+    }
+
+    let backoff = retry::Policy { retries: 0, base: Duration::from_secs(30), max: interval };
+    let mut failures = 0;
+    // A hook that failed is run again until it succeeds, even if
+    // nothing else is renewed.
+    let mut hook_pending = false;
+    loop {
+        // Looking ahead by a random part of the interval spreads out
+        // renewals of certificates issued at the same time.
+        let horizon = if watch { retry::jitter(interval) } else { Duration::from_secs(0) };
+        let mut renewed = 0;
+        let mut failed = false;
+        // Configuration is read each time around, so a watch started
+        // before smith is reachable or configured backs off like any
+        // other failure, and picks up changes to it.
+        match Configuration::try_from_env() {
+            Ok(configuration) => {
+                let api = Api::new(configuration.with_scopes(&[api::CA_SCOPE]));
+                for key in keys.iter() {
+                    match renew_for(&api, &environment, key, &names, renew_before, horizon) {
+                        Ok(Some(path)) => {
+                            eprintln!("Renewed host certificate {:?}.", path);
+                            renewed += 1;
+                        },
+                        Ok(None) => (),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            failed = true;
+                        },
+                    }
+                }
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                failed = true;
+            },
+        }
+        hook_pending = hook_pending || renewed > 0;
+        if let Some(hook) = hook.filter(|_| hook_pending) {
+            match run_hook(hook) {
+                Ok(()) => hook_pending = false,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    failed = true;
+                },
+            }
+        }
+        if !watch {
+            std::process::exit(if failed { 1 } else { 0 });
+        }
+        let delay = if failed {
+            failures += 1;
+            backoff.backoff_floored(failures - 1)
+        } else {
+            failures = 0;
+            retry::jitter(interval)
+        };
+        std::thread::sleep(delay);
+    }
+}
+
+/// Renew the certificate for `key` if it is missing, unreadable, or
+/// due within `horizon`, returning the path written.
+fn renew_for(api: &Api, environment: &Environment, key: &Path, names: &[String], renew_before: Option<Duration>, horizon: Duration) -> Result<Option<PathBuf>, String> {
+    let path = certificate_path(key);
+    let details = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|encoded| Certificate { encoded: encoded.trim().to_string() }.details());
+    let names = match details {
+        Some(ref details) if details.renew_at(renew_before) > SystemTime::now() + horizon => return Ok(None),
+        Some(ref details) if names.is_empty() => details.principals.clone(),
+        _ => names.to_vec(),
+    };
+    if names.is_empty() {
+        return Err(format!("Could not renew host certificate {:?}, it is missing and no --host-name was given.", path));
+    }
+    issue_for(api, environment, key, &names).map(Some)
+}
+
+/// Issue a certificate for the host key `key`, written next to it.
+fn issue_for(api: &Api, environment: &Environment, key: &Path, names: &[String]) -> Result<PathBuf, String> {
+    let public_key = std::fs::read_to_string(key)
+        .map_err(|e| format!("Could not read host key {:?}: {}", key, e))?;
+    let host = Some(HostName { host: names[0].to_string() });
+    let principals = names.iter().map(|name| Principal { name: name.to_string() }).collect::<Vec<_>>();
+    let request = IssueRequest::new(environment, &PublicKey { encoded: public_key.trim().to_string() })
+        .principals(&principals)
+        .host(&host);
+    let certificate = api.issue_with(&request)
+        .map_err(|e| format!("Could not issue a host certificate for {:?}: {}", key, e))?;
+    let path = certificate_path(key);
+    file::write_atomic(&path, format!("{}\n", certificate.encoded).as_bytes(), &Options::default())
+        .map_err(|e| format!("Could not write host certificate {:?}: {}", path, e))?;
+    Ok(path)
+}
+
+fn run_hook(hook: &str) -> Result<(), String> {
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .status()
+        .map_err(|e| format!("Could not run hook '{}': {}", hook, e))?;
+    if !status.success() {
+        return Err(format!("Hook '{}' failed: {}", hook, status));
+    }
+    Ok(())
+}
+
+/// `ssh_host_ed25519_key.pub` is certified by `ssh_host_ed25519_key-cert.pub`.
+fn certificate_path(key: &Path) -> PathBuf {
+    let name = key.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let stem = if name.ends_with(".pub") { &name[..name.len() - ".pub".len()] } else { &name[..] };
+    key.with_file_name(format!("{}-cert.pub", stem))
+}
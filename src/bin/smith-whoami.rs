@@ -5,7 +5,7 @@ extern crate whoami;
 use clap::App;
 
 use smith_ssh::data::UserInfo;
-use smith_ssh::api::Api;
+use smith_ssh::api::{self, Api};
 use smith_ssh::configuration::Configuration;
 
 fn main() {
@@ -14,8 +14,8 @@ fn main() {
 	.about("Request user id from server, useful for verifying authentication.")
 	.get_matches();
 
-    let configuration = Configuration::from_env();
-    let mut api = Api::new(configuration);
+    let configuration = Configuration::from_env().with_scopes(&[api::PROFILE_SCOPE]);
+    let api = Api::new(configuration);
     match api.whoami() {
         Ok(UserInfo { user_id }) => {
             println!("id = {}", user_id);
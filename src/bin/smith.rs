@@ -3,24 +3,34 @@ extern crate openssl;
 extern crate smith_ssh;
 extern crate whoami;
 
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 
 use exec::Command;
 
-use smith_ssh::agent::Agent;
-use smith_ssh::api::Api;
+use smith_ssh::agent::{self, Agent};
+use smith_ssh::api::{self, Api, Error};
 use smith_ssh::keys;
-use smith_ssh::configuration::Configuration;
-use smith_ssh::data::{Environment, Principal, PublicKey};
+use smith_ssh::configuration::{self, Configuration};
+use smith_ssh::data::{self, Certificate, Environment, IssueRequest, Principal, PublicKey};
+use smith_ssh::oauth2::{GrantError, Store, SubjectToken};
+use smith_ssh::oauth2::cache::TokenCache;
+use smith_ssh::oauth2::session::Session;
 
+use openssl::error::ErrorStack;
+use openssl::pkey::Private;
 use openssl::rsa::Rsa;
 
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
 
 fn main() {
     let matches = App::new("smith")
 	.version(&smith_ssh::version::smith_version()[..])
 	.about("Request short-lived certificate from smith.")
 	.setting(AppSettings::ArgRequiredElseHelp)
+	.setting(AppSettings::SubcommandsNegateReqs)
 	.arg(Arg::with_name("DEBUG")
 	     .short("d")
 	     .long("debug")
@@ -29,35 +39,158 @@ fn main() {
 	.arg(Arg::with_name("ENVIRONMENT")
 	     .short("e")
 	     .long("environment")
-	     .help("The environment to fetch public keys for.")
+	     .help("The environment to issue a certificate for, repeat or separate with commas for several.")
 	     .env("SMITH_ENVIRONMENT")
              .value_name("ENVIRONMENT")
+	     .multiple(true)
+	     .require_delimiter(true)
 	     .required(true))
 	.arg(Arg::with_name("PRINCIPAL")
 	     .short("p")
 	     .long("principal")
-	     .help("The principal to issue the key for.")
+	     .help("The principal to issue the key for, repeat or separate with commas for several.")
 	     .env("SMITH_PRINCIPAL")
              .value_name("PRINCIPAL")
+	     .multiple(true)
+	     .require_delimiter(true)
+	     .required(false))
+	.arg(Arg::with_name("VALID_FOR")
+	     .long("valid-for")
+	     .help("How long the certificate should be valid for, e.g. 15m or 1h30m, up to the environment's limit.")
+             .value_name("DURATION")
+	     .validator(|duration| data::parse_duration(&duration).map(|_| ()))
+	     .required(false))
+	.arg(Arg::with_name("EXTENSION")
+	     .long("extension")
+	     .help("An extension to grant instead of the environment's defaults, may be repeated.")
+             .value_name("EXTENSION")
+	     .possible_values(data::EXTENSIONS)
+	     .multiple(true)
+	     .number_of_values(1)
+	     .required(false))
+	.arg(Arg::with_name("NO_EXTENSIONS")
+	     .long("no-extensions")
+	     .help("Grant no extensions, e.g. no pty or forwarding.")
+	     .conflicts_with("EXTENSION")
+	     .required(false))
+	.arg(Arg::with_name("FORCE_COMMAND")
+	     .long("force-command")
+	     .help("Restrict the certificate to running this command.")
+             .value_name("COMMAND")
+	     .required(false))
+	.arg(Arg::with_name("SOURCE_ADDRESS")
+	     .long("source-address")
+	     .help("Restrict the certificate to these comma separated addresses or CIDR ranges.")
+             .value_name("ADDRESSES")
+	     .required(false))
+	.arg(Arg::with_name("KEY_ID")
+	     .long("key-id")
+	     .help("The key id for the certificate, recorded in sshd's logs.")
+             .value_name("ID")
+	     .required(false))
+	.arg(Arg::with_name("TOKEN_EXCHANGE_FROM_ENV")
+	     .long("token-exchange-from-env")
+	     .help("Exchange a JWT from this environment variable, e.g. a CI OIDC token, instead of using credentials.json.")
+             .value_name("VAR")
+	     .conflicts_with("SUBJECT_TOKEN_FILE")
+	     .required(false))
+	.arg(Arg::with_name("SUBJECT_TOKEN_FILE")
+	     .long("subject-token-file")
+	     .help("Exchange a JWT read from this file, e.g. a CI OIDC token, instead of using credentials.json.")
+             .value_name("PATH")
 	     .required(false))
 	.arg(Arg::from_usage("<CMD>... 'The command to run with configured ssh-agent.'")
 	     .required(false))
+	.subcommand(SubCommand::with_name("login")
+	     .about("Log in interactively, for use without a credentials.json.")
+	     .arg(Arg::with_name("BROWSER")
+		  .long("browser")
+		  .help("Log in with a browser on this machine, rather than a code.")
+		  .required(false)))
+	.subcommand(SubCommand::with_name("logout")
+	     .about("Revoke tokens, and remove local sessions, cached tokens and smith identities in the agent."))
 	.get_matches();
 
-    let environment = matches.value_of("ENVIRONMENT").unwrap_or_else(|| {
+    let debug = matches.occurrences_of("DEBUG") > 0;
+
+    if let Some(matches) = matches.subcommand_matches("login") {
+        let browser = matches.occurrences_of("BROWSER") > 0;
+        if cfg!(feature = "cli-test") {
+            println!("SMITH_CLI_SUBCOMMAND='login'");
+            println!("SMITH_CLI_BROWSER='{}'", browser);
+            std::process::exit(0)
+        }
+        login(debug, browser);
+        std::process::exit(0)
+    }
+
+    if let Some(_matches) = matches.subcommand_matches("logout") {
+        if cfg!(feature = "cli-test") {
+            println!("SMITH_CLI_SUBCOMMAND='logout'");
+            std::process::exit(0)
+        }
+        logout(debug);
+    }
+
+    let mut environments: Vec<Environment> = vec![];
+    for name in matches.values_of("ENVIRONMENT").unwrap_or_else(|| {
         eprintln!("Problem parsing arguments, no ENVIRONMENT specified.");
         std::process::exit(1);
-    });
-    let environment = Environment { name: environment.to_string() };
-    let principal = matches.value_of("PRINCIPAL").map(|p| p.to_string()).unwrap_or(whoami::username());
-    let principal = Principal { name: principal.to_string() };
+    }) {
+        let environment = Environment { name: name.to_string() };
+        if !environments.contains(&environment) {
+            environments.push(environment);
+        }
+    }
+    let principals = match matches.values_of("PRINCIPAL") {
+        Some(principals) => principals.map(|name| Principal { name: name.to_string() }).collect(),
+        None => vec![Principal { name: whoami::username() }],
+    };
+
+    let subject = match (matches.value_of("TOKEN_EXCHANGE_FROM_ENV"), matches.value_of("SUBJECT_TOKEN_FILE")) {
+        (Some(name), _) => Some(SubjectToken::Env(name.to_string())),
+        (None, Some(path)) => Some(SubjectToken::File(Path::new(path).to_path_buf())),
+        (None, None) => None,
+    };
+
+    let valid_for = matches.value_of("VALID_FOR").map(|duration| data::parse_duration(duration).unwrap_or_else(|e| {
+        eprintln!("Problem parsing arguments, {}", e);
+        std::process::exit(1);
+    }));
+    let extensions = if matches.occurrences_of("NO_EXTENSIONS") > 0 {
+        Some(vec![])
+    } else {
+        matches.values_of("EXTENSION").map(|extensions| extensions.collect::<Vec<&str>>())
+    };
+    let force_command = matches.value_of("FORCE_COMMAND");
+    let source_address = matches.value_of("SOURCE_ADDRESS");
+    let key_id = matches.value_of("KEY_ID");
 
     let command = matches.values_of("CMD");
-    let debug = matches.occurrences_of("DEBUG") > 0;
 
     if cfg!(feature = "cli-test") {
-        println!("SMITH_CLI_ENVIRONMENT='{}'", environment.name);
-        println!("SMITH_CLI_PRINCIPAL='{}'", principal.name);
+        println!("SMITH_CLI_ENVIRONMENT='{}'", environments.iter().map(|e| &e.name[..]).collect::<Vec<&str>>().join(","));
+        println!("SMITH_CLI_PRINCIPAL='{}'", principals.iter().map(|p| &p.name[..]).collect::<Vec<&str>>().join(","));
+        if let Some(valid_for) = valid_for {
+            println!("SMITH_CLI_VALID_FOR='{}'", valid_for.as_secs());
+        }
+        if let Some(ref extensions) = extensions {
+            println!("SMITH_CLI_EXTENSIONS='{}'", extensions.join(","));
+        }
+        if let Some(force_command) = force_command {
+            println!("SMITH_CLI_FORCE_COMMAND='{}'", force_command);
+        }
+        if let Some(source_address) = source_address {
+            println!("SMITH_CLI_SOURCE_ADDRESS='{}'", source_address);
+        }
+        if let Some(key_id) = key_id {
+            println!("SMITH_CLI_KEY_ID='{}'", key_id);
+        }
+        match subject {
+            Some(SubjectToken::Env(ref name)) => println!("SMITH_CLI_SUBJECT_TOKEN='env:{}'", name),
+            Some(SubjectToken::File(ref path)) => println!("SMITH_CLI_SUBJECT_TOKEN='file:{}'", path.display()),
+            None => (),
+        }
         if let Some(command) = command {
             let command = command.into_iter().collect::<Vec<&str>>().join(" ");
             println!("SMITH_CLI_COMMAND='{}'", command);
@@ -68,35 +201,259 @@ fn main() {
         eprintln!("Could not connect to ssh-agent.");
         std::process::exit(1);
     });
-    let configuration = Configuration::from_env();
-    let mut api = Api::new(configuration);
-    let keys = Rsa::generate(4096).unwrap_or_else(|e| {
-        eprintln!("Could not generate an RSA key pair: {}", e);
+    let configuration = match subject {
+        Some(subject) => Configuration::from_env_for_token_exchange(subject),
+        None => Configuration::from_env(),
+    }.with_scopes(&[api::CA_SCOPE]);
+    let api = Arc::new(Api::new(configuration));
+    let requests = environments.iter().map(|environment| {
+        // The public key is filled in once the key pair is generated.
+        let mut request = IssueRequest::new(environment, &PublicKey { encoded: String::new() }).principals(&principals);
+        if let Some(valid_for) = valid_for {
+            request = request.valid_for(valid_for);
+        }
+        if let Some(ref extensions) = extensions {
+            request = request.extensions(extensions);
+        }
+        if let Some(force_command) = force_command {
+            request = request.force_command(force_command);
+        }
+        if let Some(source_address) = source_address {
+            request = request.source_address(source_address);
+        }
+        if let Some(key_id) = key_id {
+            request = request.key_id(key_id);
+        }
+        request
+    }).collect();
+    let mut issued = issue_all(&api, requests);
+    if issued.iter().any(|(_, result)| match result { Ok((_, Err(Error::GrantError(GrantError::LoginRequired)))) => true, _ => false }) {
+        eprintln!("Your login session has expired, logging in again.");
+        login(debug, false);
+        for (request, result) in issued.iter_mut() {
+            if let Ok((_, ref mut result)) = result {
+                if let Err(Error::GrantError(GrantError::LoginRequired)) = result {
+                    *result = api.issue_with(request);
+                }
+            }
+        }
+    }
+    let mut failed = false;
+    for (request, result) in issued {
+        let (keys, certificate) = match result {
+            Ok((keys, Ok(certificate))) => (keys, certificate),
+            Ok((_, Err(e))) => {
+                eprintln!("Could not issue a certificate for {}: {}", request.environment.name, e);
+                if debug {
+                    eprintln!("DEBUG: {:?}", e);
+                }
+                failed = true;
+                continue;
+            },
+            Err(e) => {
+                eprintln!("Could not generate an RSA key pair for {}: {}", request.environment.name, e);
+                if debug {
+                    eprintln!("DEBUG: {:?}", e);
+                }
+                failed = true;
+                continue;
+            },
+        };
+        agent.add_certificate(&keys, &certificate).unwrap_or_else(|e| {
+            eprintln!("Could not add certificate to agent: {}", e);
+            if debug {
+                eprintln!("DEBUG: {:?}", e);
+            }
+            std::process::exit(1);
+        });
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    if let Some(command) = command {
+        let command = command.into_iter().collect::<Vec<&str>>();
+        let result = Command::new(&command[0]).args(&command[1..]).exec();
+        eprintln!("Could not execute command: {}", result);
+        std::process::exit(1)
+    }
+}
+
+/// A request, with its key pair and the certificate issued for it, or
+/// why the key pair couldn't be generated.
+type Issued = (IssueRequest, Result<(Rsa<Private>, Result<Certificate, Error>), ErrorStack>);
+
+/// Generate a key pair and issue a certificate for each request, in
+/// parallel as generating RSA keys is slow. A request whose key pair
+/// couldn't be generated is never sent.
+fn issue_all(api: &Arc<Api>, requests: Vec<IssueRequest>) -> Vec<Issued> {
+    let handles = requests.into_iter().map(|mut request| {
+        let api = api.clone();
+        thread::spawn(move || {
+            let result = Rsa::generate(4096).map(|keys| {
+                let comment = agent::comment(&Some(request.environment.name.clone()));
+                request.public_key = PublicKey { encoded: keys::encode_ssh(&keys, &comment) };
+                let result = api.issue_with(&request);
+                (keys, result)
+            });
+            (request, result)
+        })
+    }).collect::<Vec<_>>();
+    handles.into_iter().map(|handle| handle.join().unwrap_or_else(|_| {
+        eprintln!("Could not issue certificates, a request failed unexpectedly.");
+        std::process::exit(1);
+    })).collect()
+}
+
+fn login(debug: bool, browser: bool) {
+    let configuration = Configuration::from_env_for_login();
+    let store = configuration.oauth2.initialise_with(configuration.client.clone());
+    let session = if browser {
+        login_with_browser(&store, debug)
+    } else {
+        login_with_device(&store, debug)
+    };
+    session.save(&configuration.session_path()).unwrap_or_else(|e| {
+        eprintln!("Could not save login session to {:?}.", configuration.session_path());
         if debug {
             eprintln!("DEBUG: {:?}", e);
         }
         std::process::exit(1);
     });
-    let encoded = keys::encode_ssh(&keys, "comment");
-    let public = PublicKey { encoded };
-    let certificate = api.issue(&environment, &public, &vec![principal], &None).unwrap_or_else(|e| {
-        eprintln!("Could not issue a certificate: {}", e);
+    eprintln!("Logged in.");
+}
+
+fn login_with_browser(store: &Store, debug: bool) -> Session {
+    store.login_with_browser(|url| {
+        eprintln!("To log in, visit: {}", url);
+        let opener = if cfg!(target_os = "macos") { "open" } else { "xdg-open" };
+        // The url is printed regardless, a missing opener is not fatal.
+        let _ = std::process::Command::new(opener)
+            .arg(url)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn();
+        Ok(())
+    }).unwrap_or_else(|e| {
+        eprintln!("Could not log in, the login was denied or timed out, please retry.");
+        if debug {
+            eprintln!("DEBUG: {:?}", e);
+        }
+        std::process::exit(1);
+    })
+}
+
+fn login_with_device(store: &Store, debug: bool) -> Session {
+    let authorization = store.authorize_device().unwrap_or_else(|e| {
+        eprintln!("Could not start login, please check connectivity to Smith and retry.");
         if debug {
             eprintln!("DEBUG: {:?}", e);
         }
         std::process::exit(1);
     });
-    agent.add_certificate(&keys, &certificate).unwrap_or_else(|e| {
-        eprintln!("Could not add certificate to agent: {}", e);
+    eprintln!("To log in, visit {} and enter the code: {}", authorization.verification_uri, authorization.user_code);
+    if let Some(ref uri) = authorization.verification_uri_complete {
+        eprintln!("Or visit: {}", uri);
+    }
+    store.poll_device(&authorization).unwrap_or_else(|e| {
+        eprintln!("Could not log in, the login was denied or expired, please retry.");
         if debug {
             eprintln!("DEBUG: {:?}", e);
         }
         std::process::exit(1);
+    })
+}
+
+/// Local state is removed even if revocation fails, so the exit status
+/// reports whether everything was revoked.
+fn logout(debug: bool) -> ! {
+    // Local paths don't need the network configuration, so a broken
+    // or unreachable configuration never stops the local cleanup.
+    let home = configuration::home_from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
     });
-    if let Some(command) = command {
-        let command = command.into_iter().collect::<Vec<&str>>();
-        let result = Command::new(&command[0]).args(&command[1..]).exec();
-        eprintln!("Could not execute command: {}", result);
-        std::process::exit(1)
+    let session_path = home.join("session.json");
+    let cache = TokenCache::new(home.join("cache"));
+
+    // Revocation is best effort, tokens that can't be revoked are
+    // still removed and expire on their own.
+    let store = match Configuration::try_from_env_for_login() {
+        Ok(configuration) => Some(configuration.oauth2.initialise_with(configuration.client.clone())),
+        Err(e) => {
+            if debug {
+                eprintln!("DEBUG: {:?}", e);
+            }
+            None
+        },
+    };
+    let mut revoked = true;
+    let mut revoke = |token: &str, hint: &str| {
+        match store {
+            Some(ref store) => if let Err(e) = store.revoke(token, hint) {
+                revoked = false;
+                if debug {
+                    eprintln!("DEBUG: {:?}", e);
+                }
+            },
+            None => revoked = false,
+        }
+    };
+
+    match Session::load(&session_path) {
+        Ok(Some(session)) => {
+            if let Some(ref refresh_token) = session.refresh_token {
+                revoke(refresh_token, "refresh_token");
+            }
+            revoke(&session.access_token, "access_token");
+        },
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("Could not read login session {:?}, it will be removed without being revoked.", session_path);
+            if debug {
+                eprintln!("DEBUG: {:?}", e);
+            }
+        },
+    }
+    for state in cache.tokens().unwrap_or_default() {
+        revoke(&state.token.value, "access_token");
+    }
+
+    let mut removed = true;
+    if let Err(e) = std::fs::remove_file(&session_path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            eprintln!("Could not remove login session {:?}.", session_path);
+            if debug {
+                eprintln!("DEBUG: {:?}", e);
+            }
+            removed = false;
+        }
+    }
+    if let Err(e) = cache.clear() {
+        eprintln!("Could not remove token cache {:?}.", cache.directory);
+        if debug {
+            eprintln!("DEBUG: {:?}", e);
+        }
+        removed = false;
+    }
+    if let Some(mut agent) = Agent::connect() {
+        match agent.remove_smith_identities() {
+            Ok(count) => eprintln!("Removed {} smith identities from the agent.", count),
+            Err(e) => {
+                eprintln!("Could not remove smith identities from agent: {}", e);
+                if debug {
+                    eprintln!("DEBUG: {:?}", e);
+                }
+                removed = false;
+            },
+        }
+    }
+
+    if !removed {
+        std::process::exit(1);
+    }
+    if !revoked {
+        eprintln!("WARNING: Some tokens could not be revoked, please check connectivity to Smith, they will expire on their own.");
     }
+    eprintln!("Logged out.");
+    std::process::exit(0)
 }
@@ -1,10 +1,18 @@
+use crate::authority::AuthorityCache;
+use crate::http;
+use crate::jws::Jwk;
 use crate::oauth2;
+use crate::oauth2::discovery::{Discovery, MetadataCache};
+use crate::pinning::{PinError, Pinning};
+use crate::retry;
 
-use biscuit::jwk::JWK;
 use std::fs::File;
 use std::io::prelude::*;
+use std::fmt;
 use std::io::ErrorKind;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct IdentityId {
@@ -14,63 +22,295 @@ pub struct IdentityId {
 
 #[derive(Clone)]
 pub struct Configuration {
+    pub home: PathBuf,
     pub endpoint: String,
-    pub jwk: JWK<IdentityId>,
+    pub jwk: Option<Jwk<IdentityId>>,
     pub oauth2: oauth2::Configuration,
+    /// Scopes from `SMITH_SCOPES`, requested in place of the scopes
+    /// each operation needs.
+    pub scopes: Option<Vec<String>>,
+    /// The client for every request, see `http::Settings`.
+    pub client: reqwest::Client,
+    pub http: http::Settings,
+}
+
+#[derive(Debug)]
+pub enum ConfigurationError {
+    HomeError,
+    JwkParseError(String, serde_json::Error),
+    CredentialsReadError(PathBuf, std::io::Error),
+    InvalidJwkError(oauth2::KeyError),
+    MissingCredentialsError(PathBuf),
+    InvalidNumberError(String, std::num::ParseIntError),
+    HttpError(http::Error),
+    PinningError(PinError),
+}
+
+impl fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigurationError::HomeError =>
+              write!(f, "Could not determine home directory, please set SMITH_HOME explicity."),
+            ConfigurationError::JwkParseError(source, _) =>
+              write!(f, "JWK could not be parsed from {}, check it is a well formatted JWK from https://smith.st.", source),
+            ConfigurationError::CredentialsReadError(path, _) =>
+              write!(f, "credentials.json could not be read, check permissions, tried: {:?}", path),
+            ConfigurationError::InvalidJwkError(_) =>
+              write!(f, "JWK is not valid, check it is a JWK from https://smith.st."),
+            ConfigurationError::MissingCredentialsError(path) =>
+              write!(f, "credentials.json could not be found, check it exists or run `smith login`, tried: {:?}", path),
+            ConfigurationError::InvalidNumberError(name, _) =>
+              write!(f, "{} could not be parsed, it should be a number.", name),
+            ConfigurationError::HttpError(e) =>
+              write!(f, "{}", e),
+            ConfigurationError::PinningError(e) =>
+              write!(f, "{}", e),
+        }
+    }
 }
 
 impl Configuration {
+    /// Configuration for API credentials, from a JWK if one is
+    /// available, falling back to a session from `smith login`.
+    /// Exits if it can't be built, see `try_from_env`.
     pub fn from_env() -> Configuration {
-        let home = std::env::var("SMITH_HOME")
-            .map(|h| Path::new(&h).to_path_buf())
-            .unwrap_or_else(|_| {
-                dirs::home_dir().map(|home| home.join(".smith").to_path_buf()).unwrap_or_else(|| {
-                    eprintln!("Could not determine home directory, please set SMITH_HOME explicity.");
-                    std::process::exit(1);
+        Configuration::try_from_env().unwrap_or_else(|err| exit_with(err))
+    }
+
+    /// Like `from_env`, but returning why the configuration couldn't
+    /// be built, for programs that can carry on without smith.
+    pub fn try_from_env() -> Result<Configuration, ConfigurationError> {
+        let home = home_from_env()?;
+        let session = home.join("session.json");
+        let jwk = jwk_from_env(&home)?;
+        let credentials = match jwk {
+            Some(ref jwk) => {
+                let key = oauth2::Configuration::build_secret(jwk).map_err(|e| ConfigurationError::InvalidJwkError(e))?;
+                oauth2::Credentials::Assertion(oauth2::AssertionCredentials {
+                    key: key,
+                    key_id: jwk.key_id(),
+                    issuer: format!("{}", jwk.additional().value),
                 })
-            });
+            },
+            None if session.exists() => oauth2::Credentials::Session(session),
+            None => return Err(ConfigurationError::MissingCredentialsError(home.join("credentials.json"))),
+        };
+        Configuration::from_env_with(home, jwk, credentials)
+    }
+
+    /// Configuration for an interactive login, without credentials.
+    pub fn from_env_for_login() -> Configuration {
+        Configuration::try_from_env_for_login().unwrap_or_else(|err| exit_with(err))
+    }
+
+    /// Like `from_env_for_login`, but returning why the configuration
+    /// couldn't be built, so logging out can still clean up locally.
+    pub fn try_from_env_for_login() -> Result<Configuration, ConfigurationError> {
+        let home = home_from_env()?;
+        let credentials = oauth2::Credentials::Session(home.join("session.json"));
+        Configuration::from_env_with(home, None, credentials)
+    }
+
+    /// Configuration for exchanging a third-party JWT, without a JWK.
+    pub fn from_env_for_token_exchange(subject: oauth2::SubjectToken) -> Configuration {
+        let home = home_from_env().unwrap_or_else(|err| exit_with(err));
+        let credentials = oauth2::Credentials::TokenExchange(subject);
+        Configuration::from_env_with(home, None, credentials).unwrap_or_else(|err| exit_with(err))
+    }
+
+    fn from_env_with(home: PathBuf, jwk: Option<Jwk<IdentityId>>, credentials: oauth2::Credentials) -> Result<Configuration, ConfigurationError> {
         let endpoint = std::env::var("SMITH_ENDPOINT").unwrap_or("https://api.smith.st".to_string());
-        let jwk: JWK<IdentityId> = std::env::var("SMITH_JWK")
-            .map(|jwk| {
-                serde_json::from_str(&jwk).unwrap_or_else(|err| {
-                     eprintln!("JWK could not be parsed from environment variable SMITH_JWK, check it is a well formatted JWK from https://smith.st: {:?}", err);
-                     std::process::exit(1);
-                })
-            })
-            .unwrap_or_else(|_| {
-                let credentials = home.join("credentials.json");
-                let mut file = match File::open(&credentials) {
-                    Ok(file) => file,
-                    Err(err) =>
-                        if err.kind() == ErrorKind::NotFound {
-                            eprintln!("credentials.json could not be found, check it exists, tried: {:?}", credentials);
-                            std::process::exit(1);
-                        } else {
-                            eprintln!("credentials.json could not be accessed, check permissions, tried: {:?}", credentials);
-                            std::process::exit(1);
-                        }
-                };
-                let mut contents = String::new();
-                file.read_to_string(&mut contents).unwrap_or_else(|_| {
-                    eprintln!("credentials.json could not be read, check file, tried: {:?}", credentials);
-                    std::process::exit(1);
-                });
-                serde_json::from_str(&contents).unwrap_or_else(|err| {
-                     eprintln!("JWK could not be parsed from file ['{:?}']: {:?}", credentials, err);
-                     std::process::exit(1);
-                })
-            });
-        let key = oauth2::Configuration::build_secret(&jwk).unwrap_or_else(|err| {
-            eprintln!("JWK is not valid: {:?}", err);
-            std::process::exit(1);
-        });
+        let client_id = std::env::var("SMITH_CLIENT_ID").unwrap_or("smith-cli".to_string());
+        let assertion_lifetime = seconds_from_env("SMITH_ASSERTION_LIFETIME", 60)?;
+        let refresh_margin = seconds_from_env("SMITH_TOKEN_REFRESH_MARGIN", 60)?;
+        let cache = match std::env::var("SMITH_TOKEN_CACHE") {
+            Ok(ref setting) if setting == "disabled" => None,
+            _ => Some(home.join("cache")),
+        };
+        let settings = http_from_env()?;
+        let client = settings.client().map_err(|e| ConfigurationError::HttpError(e))?;
+        let pins = pins_from_env(&settings)?.map(Arc::new);
+        let discovery = discovery_from_env(&home, &endpoint)?;
+        let dpop = match std::env::var("SMITH_DPOP") {
+            Ok(ref setting) if setting == "enabled" => Some(true),
+            Ok(ref setting) if setting == "disabled" => Some(false),
+            _ => None,
+        };
+        let token_endpoint = std::env::var("SMITH_TOKEN_ENDPOINT").unwrap_or(format!("{}/oauth/token", &endpoint));
+        let audience = std::env::var("SMITH_AUDIENCE").unwrap_or("https://smith.st".to_string());
+        let scopes = scopes_from_env();
+        let retry = retry::Policy { retries: retries_from_env()?, ..retry::Policy::default() };
         let oauth2 = oauth2::Configuration {
-            key: key,
-            endpoint: format!("{}/oauth/token", &endpoint),
-            issuer: format!("{}", jwk.additional.value),
-            audience: "https://smith.st".to_string(),
-            scopes: vec!["profile".to_string(), "ca".to_string()],
+            credentials: credentials,
+            client_id: client_id,
+            endpoint: token_endpoint,
+            device_endpoint: format!("{}/oauth/device/code", &endpoint),
+            authorization_endpoint: format!("{}/oauth/authorize", &endpoint),
+            revocation_endpoint: Some(format!("{}/oauth/revoke", &endpoint)),
+            audience: audience,
+            scopes: scopes.clone().unwrap_or(vec!["profile".to_string(), "ca".to_string()]),
+            assertion_lifetime: assertion_lifetime,
+            refresh_margin: refresh_margin,
+            cache: cache,
+            dpop: dpop,
+            discovery: discovery,
+            retry: retry,
+            pins: pins,
         };
-        Configuration { endpoint, jwk, oauth2 }
+        Ok(Configuration { home, endpoint, jwk, oauth2, scopes, client, http: settings })
+    }
+
+    /// The scopes this program needs, requested on login and for the
+    /// default token, unless overridden by `SMITH_SCOPES`.
+    pub fn with_scopes(mut self, scopes: &[&str]) -> Configuration {
+        if self.scopes.is_none() {
+            self.oauth2.scopes = scopes.iter().map(|scope| scope.to_string()).collect();
+        }
+        self
+    }
+
+    pub fn session_path(&self) -> PathBuf {
+        self.home.join("session.json")
+    }
+}
+
+#[cfg(test)]
+impl Configuration {
+    /// Configuration for the mock server at `server`, with the test JWK.
+    pub(crate) fn for_tests(server: &str) -> Configuration {
+        let contents = std::fs::read_to_string("test/data/credentials.json").expect("Should be able to read credentials file.");
+        let jwk = serde_json::from_str(&contents).expect("Should be able to deserialise credentials file.");
+        let settings = http::Settings::default();
+        Configuration {
+            home: std::env::temp_dir(),
+            endpoint: server.to_string(),
+            jwk: Some(jwk),
+            oauth2: oauth2::Configuration::for_tests(server),
+            scopes: None,
+            client: settings.client().expect("Client should build."),
+            http: settings,
+        }
+    }
+}
+
+/// The CA key cache for `smith-host`, in '$SMITH_CA_CACHE' or
+/// '$SMITH_HOME/authority', apart from the token cache so logging out
+/// doesn't remove it. It doesn't need credentials, so it can be
+/// seeded when building an image.
+pub fn authority_cache_from_env() -> AuthorityCache {
+    let directory = std::env::var("SMITH_CA_CACHE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| home_from_env().unwrap_or_else(|err| exit_with(err)).join("authority"));
+    AuthorityCache::new(directory)
+}
+
+/// '$SMITH_HOME' or '~/.smith', where the login session and token
+/// cache are kept.
+pub fn home_from_env() -> Result<PathBuf, ConfigurationError> {
+    match std::env::var("SMITH_HOME") {
+        Ok(home) => Ok(Path::new(&home).to_path_buf()),
+        Err(_) => dirs::home_dir().map(|home| home.join(".smith")).ok_or(ConfigurationError::HomeError),
+    }
+}
+
+fn jwk_from_env(home: &Path) -> Result<Option<Jwk<IdentityId>>, ConfigurationError> {
+    match std::env::var("SMITH_JWK") {
+        Ok(jwk) => {
+            let jwk = serde_json::from_str(&jwk)
+                .map_err(|e| ConfigurationError::JwkParseError("environment variable SMITH_JWK".to_string(), e))?;
+            Ok(Some(jwk))
+        },
+        Err(_) => {
+            let credentials = home.join("credentials.json");
+            let mut file = match File::open(&credentials) {
+                Ok(file) => file,
+                Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(ConfigurationError::CredentialsReadError(credentials, err)),
+            };
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)
+                .map_err(|e| ConfigurationError::CredentialsReadError(credentials.clone(), e))?;
+            let jwk = serde_json::from_str(&contents)
+                .map_err(|e| ConfigurationError::JwkParseError(format!("{:?}", credentials), e))?;
+            Ok(Some(jwk))
+        },
+    }
+}
+
+/// Where to discover the OAuth endpoints from, unless discovery is
+/// disabled, it happens on first use. When `SMITH_DISCOVERY=enabled`
+/// the metadata is required.
+fn discovery_from_env(home: &Path, endpoint: &str) -> Result<Option<Discovery>, ConfigurationError> {
+    let ttl = seconds_from_env("SMITH_DISCOVERY_TTL", 3600)?;
+    let required = match std::env::var("SMITH_DISCOVERY") {
+        Ok(ref setting) if setting == "disabled" => return Ok(None),
+        Ok(ref setting) => setting == "enabled",
+        Err(_) => false,
+    };
+    Ok(Some(Discovery {
+        issuer: endpoint.to_string(),
+        cache: MetadataCache::new(home.join("cache"), ttl),
+        required,
+        keep_token_endpoint: std::env::var("SMITH_TOKEN_ENDPOINT").is_ok(),
+    }))
+}
+
+/// Settings for the shared HTTP client, timeouts are `SMITH_CONNECT_TIMEOUT` and
+/// `SMITH_TIMEOUT` seconds, proxies come from the usual variables.
+fn http_from_env() -> Result<http::Settings, ConfigurationError> {
+    Ok(http::Settings {
+        connect_timeout: seconds_from_env("SMITH_CONNECT_TIMEOUT", 10)?,
+        timeout: seconds_from_env("SMITH_TIMEOUT", 30)?,
+        proxies: http::Proxies::from_env().map_err(|e| ConfigurationError::HttpError(e))?,
+        ca_bundle: std::env::var("SMITH_CA_BUNDLE").ok().map(PathBuf::from),
+        identity: std::env::var("SMITH_CLIENT_CERTIFICATE").ok().map(|path| {
+            (PathBuf::from(path), std::env::var("SMITH_CLIENT_CERTIFICATE_PASSWORD").unwrap_or_default())
+        }),
+    })
+}
+
+/// Public key pins from `SMITH_PINS`, comma separated `sha256/<base64>`
+/// hashes, checked for every request to smith.
+fn pins_from_env(settings: &http::Settings) -> Result<Option<Pinning>, ConfigurationError> {
+    let pins = match std::env::var("SMITH_PINS") {
+        Ok(pins) => pins,
+        Err(_) => return Ok(None),
+    };
+    let pins: Vec<String> = pins
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|pin| !pin.is_empty())
+        .map(|pin| pin.to_string())
+        .collect();
+    Pinning::new(&pins, settings).map(Some).map_err(|e| ConfigurationError::PinningError(e))
+}
+
+fn exit_with<E: fmt::Display + fmt::Debug>(err: E) -> ! {
+    eprintln!("{} {:?}", err, err);
+    std::process::exit(1);
+}
+
+fn scopes_from_env() -> Option<Vec<String>> {
+    std::env::var("SMITH_SCOPES").ok().map(|scopes| {
+        scopes
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|scope| !scope.is_empty())
+            .map(|scope| scope.to_string())
+            .collect()
+    })
+}
+
+/// Retries for requests that fail transiently, `SMITH_RETRIES=0`
+/// disables retrying.
+fn retries_from_env() -> Result<u32, ConfigurationError> {
+    match std::env::var("SMITH_RETRIES") {
+        Ok(retries) => retries.parse::<u32>().map_err(|e| ConfigurationError::InvalidNumberError("SMITH_RETRIES".to_string(), e)),
+        Err(_) => Ok(retry::Policy::default().retries),
+    }
+}
+
+fn seconds_from_env(name: &str, default: u64) -> Result<Duration, ConfigurationError> {
+    match std::env::var(name) {
+        Ok(seconds) => seconds.parse::<u64>().map(Duration::from_secs).map_err(|e| ConfigurationError::InvalidNumberError(name.to_string(), e)),
+        Err(_) => Ok(Duration::from_secs(default)),
     }
 }
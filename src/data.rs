@@ -1,3 +1,9 @@
+use crate::codec;
+
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct UserInfo {
@@ -31,6 +37,147 @@ pub struct HostName {
     pub host: String
 }
 
+/// Extensions a certificate can grant, see PROTOCOL.certkeys.
+pub const EXTENSIONS: &[&str] = &[
+    "no-touch-required",
+    "permit-X11-forwarding",
+    "permit-agent-forwarding",
+    "permit-port-forwarding",
+    "permit-pty",
+    "permit-user-rc",
+];
+
+/// Critical options a certificate can be restricted with.
+pub const CRITICAL_OPTIONS: &[&str] = &[
+    "force-command",
+    "source-address",
+];
+
+/// A request for a certificate, anything not set is left to the
+/// environment's defaults.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IssueRequest {
+    pub environment: Environment,
+    pub public_key: PublicKey,
+    pub principals: Vec<Principal>,
+    pub host: Option<HostName>,
+    pub valid_for: Option<Duration>,
+    /// The exact extensions to grant, `Some(vec![])` for none.
+    pub extensions: Option<Vec<String>>,
+    pub critical_options: BTreeMap<String, String>,
+    pub key_id: Option<String>,
+}
+
+impl IssueRequest {
+    pub fn new(environment: &Environment, public_key: &PublicKey) -> IssueRequest {
+        IssueRequest {
+            environment: environment.clone(),
+            public_key: public_key.clone(),
+            principals: vec![],
+            host: None,
+            valid_for: None,
+            extensions: None,
+            critical_options: BTreeMap::new(),
+            key_id: None,
+        }
+    }
+
+    pub fn principals(mut self, principals: &[Principal]) -> IssueRequest {
+        self.principals = principals.to_vec();
+        self
+    }
+
+    pub fn host(mut self, host: &Option<HostName>) -> IssueRequest {
+        self.host = host.clone();
+        self
+    }
+
+    pub fn valid_for(mut self, valid_for: Duration) -> IssueRequest {
+        self.valid_for = Some(valid_for);
+        self
+    }
+
+    pub fn extensions(mut self, extensions: &[&str]) -> IssueRequest {
+        self.extensions = Some(extensions.iter().map(|extension| extension.to_string()).collect());
+        self
+    }
+
+    pub fn critical_option(mut self, name: &str, value: &str) -> IssueRequest {
+        self.critical_options.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    pub fn force_command(self, command: &str) -> IssueRequest {
+        self.critical_option("force-command", command)
+    }
+
+    pub fn source_address(self, addresses: &str) -> IssueRequest {
+        self.critical_option("source-address", addresses)
+    }
+
+    pub fn key_id(mut self, key_id: &str) -> IssueRequest {
+        self.key_id = Some(key_id.to_string());
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut request = json!({
+            "public-key": self.public_key.encoded,
+            "principals": self.principals.iter().map(|p| &p.name).collect::<Vec<_>>(),
+            "environment": self.environment.name,
+            "host-name": self.host.as_ref().map(|h| &h.host),
+        });
+        if let Some(valid_for) = self.valid_for {
+            request["valid-for"] = json!(valid_for.as_secs());
+        }
+        if let Some(ref extensions) = self.extensions {
+            request["extensions"] = json!(extensions);
+        }
+        if !self.critical_options.is_empty() {
+            request["critical-options"] = json!(self.critical_options);
+        }
+        if let Some(ref key_id) = self.key_id {
+            request["key-id"] = json!(key_id);
+        }
+        request
+    }
+}
+
+/// Parse a duration such as `90s`, `15m`, `1h30m` or `7d`, a number
+/// on its own is seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("'{}' is not a duration, e.g. 90s, 15m, 1h30m or 7d.", duration);
+    let duration = duration.trim();
+    if duration.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(seconds) = duration.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in duration.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let value = number.parse::<u64>().map_err(|_e| invalid())?;
+        total = value.checked_mul(unit).and_then(|seconds| total.checked_add(seconds)).ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(total))
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Certificate {
     #[serde(rename = "certificate")]
@@ -44,7 +191,62 @@ pub struct DeconstructedCertificate {
     pub comment: Option<String>,
 }
 
+/// The fields of a certificate needed to decide when to renew it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CertificateDetails {
+    pub key_id: String,
+    pub principals: Vec<String>,
+    pub valid_after: SystemTime,
+    pub valid_before: SystemTime,
+}
+
+impl CertificateDetails {
+    /// When the certificate should be renewed, `renew_before` its
+    /// expiry, or with a third of its validity left by default.
+    pub fn renew_at(&self, renew_before: Option<Duration>) -> SystemTime {
+        let lifetime = self.valid_before.duration_since(self.valid_after).unwrap_or_default();
+        let renew_before = std::cmp::min(renew_before.unwrap_or(lifetime / 3), lifetime);
+        self.valid_before - renew_before
+    }
+}
+
 impl Certificate {
+    /// Read the certificate's key id, principals and validity, see
+    /// PROTOCOL.certkeys.
+    pub fn details(&self) -> Option<CertificateDetails> {
+        let certificate = self.deconstruct()?;
+        let mut reader = Cursor::new(certificate.blob);
+        let key_type = codec::decode_string(&mut reader).ok()?;
+        // The nonce, then the certified public key's fields.
+        let fields = 1 + match &key_type[..] {
+            "ssh-ed25519-cert-v01@openssh.com" => 1,
+            "ssh-rsa-cert-v01@openssh.com" => 2,
+            "ecdsa-sha2-nistp256-cert-v01@openssh.com"
+                | "ecdsa-sha2-nistp384-cert-v01@openssh.com"
+                | "ecdsa-sha2-nistp521-cert-v01@openssh.com"
+                | "sk-ssh-ed25519-cert-v01@openssh.com" => 2,
+            "sk-ecdsa-sha2-nistp256-cert-v01@openssh.com" => 3,
+            "ssh-dss-cert-v01@openssh.com" => 4,
+            _ => return None,
+        };
+        for _ in 0..fields {
+            codec::decode_bytes(&mut reader).ok()?;
+        }
+        let _serial = codec::decode_uint64(&mut reader).ok()?;
+        let _type = codec::decode_uint32(&mut reader).ok()?;
+        let key_id = codec::decode_string(&mut reader).ok()?;
+        let mut packed = Cursor::new(codec::decode_bytes(&mut reader).ok()?);
+        let mut principals = vec![];
+        while (packed.position() as usize) < packed.get_ref().len() {
+            principals.push(codec::decode_string(&mut packed).ok()?);
+        }
+        let valid_after = codec::decode_uint64(&mut reader).ok()?;
+        let valid_before = codec::decode_uint64(&mut reader).ok()?;
+        // `valid_before` is all ones for a certificate that never expires.
+        let time = |seconds: u64| UNIX_EPOCH.checked_add(Duration::from_secs(std::cmp::min(seconds, i64::max_value() as u64 / 2)));
+        Some(CertificateDetails { key_id, principals, valid_after: time(valid_after)?, valid_before: time(valid_before)? })
+    }
+
     pub fn deconstruct(&self) -> Option<DeconstructedCertificate> {
         let parts = self.encoded.split(' ').collect::<Vec<_>>();
         if parts.len() < 2 || parts.len() > 3 {
@@ -60,3 +262,83 @@ impl Certificate {
         Some(DeconstructedCertificate { key_type, blob, comment })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("15").is_ok());
+        assert!(parse_duration("15x").is_err());
+        assert!(parse_duration("1h30").is_err());
+    }
+
+    fn test_certificate(valid_after: u64, valid_before: u64) -> Certificate {
+        let mut blob = vec![];
+        codec::encode_string(&mut blob, "ssh-ed25519-cert-v01@openssh.com").unwrap();
+        codec::encode_bytes(&mut blob, &[0; 32]).unwrap();
+        codec::encode_bytes(&mut blob, &[1; 32]).unwrap();
+        codec::encode_uint64(&mut blob, 7).unwrap();
+        codec::encode_uint32(&mut blob, 2).unwrap();
+        codec::encode_string(&mut blob, "web1").unwrap();
+        let mut principals = vec![];
+        codec::encode_string(&mut principals, "web1.example.com").unwrap();
+        codec::encode_string(&mut principals, "web1").unwrap();
+        codec::encode_bytes(&mut blob, &principals).unwrap();
+        codec::encode_uint64(&mut blob, valid_after).unwrap();
+        codec::encode_uint64(&mut blob, valid_before).unwrap();
+        Certificate { encoded: format!("ssh-ed25519-cert-v01@openssh.com {}", base64::encode(&blob)) }
+    }
+
+    #[test]
+    fn test_details() {
+        let details = test_certificate(1000, 4000).details().expect("Certificate should be readable.");
+        assert_eq!(details.key_id, "web1");
+        assert_eq!(details.principals, vec!["web1.example.com".to_string(), "web1".to_string()]);
+        assert_eq!(details.valid_after, UNIX_EPOCH + Duration::from_secs(1000));
+        assert_eq!(details.valid_before, UNIX_EPOCH + Duration::from_secs(4000));
+        assert!(test_certificate(0, u64::max_value()).details().is_some());
+        assert!(Certificate { encoded: "ssh-ed25519-cert-v01@openssh.com AAAA".to_string() }.details().is_none());
+    }
+
+    #[test]
+    fn test_renew_at() {
+        let details = test_certificate(1000, 4000).details().expect("Certificate should be readable.");
+        assert_eq!(details.renew_at(None), UNIX_EPOCH + Duration::from_secs(3000));
+        assert_eq!(details.renew_at(Some(Duration::from_secs(500))), UNIX_EPOCH + Duration::from_secs(3500));
+        assert_eq!(details.renew_at(Some(Duration::from_secs(5000))), UNIX_EPOCH + Duration::from_secs(1000));
+    }
+
+    #[test]
+    fn test_issue_request() {
+        let environment = Environment { name: "prod".to_string() };
+        let public_key = PublicKey { encoded: "ssh-rsa AAAA".to_string() };
+        let request = IssueRequest::new(&environment, &public_key)
+            .principals(&[Principal { name: "deploy".to_string() }])
+            .valid_for(Duration::from_secs(900))
+            .extensions(&[])
+            .force_command("/usr/local/bin/deploy")
+            .key_id("release-42");
+        assert_eq!(request.to_json(), json!({
+            "public-key": "ssh-rsa AAAA",
+            "principals": ["deploy"],
+            "environment": "prod",
+            "host-name": null,
+            "valid-for": 900,
+            "extensions": [],
+            "critical-options": { "force-command": "/usr/local/bin/deploy" },
+            "key-id": "release-42",
+        }));
+        let defaults = IssueRequest::new(&environment, &public_key).to_json();
+        assert!(defaults.get("extensions").is_none());
+        assert!(defaults.get("critical-options").is_none());
+    }
+}
@@ -0,0 +1,213 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// How to write a file, the mode and owner of a file being replaced
+/// are kept unless they are set.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Options {
+    pub mode: Option<u32>,
+    /// The uid and gid to own the file.
+    pub owner: Option<(u32, u32)>,
+    /// Keep the file being replaced as `<path>.bak`.
+    pub backup: bool,
+}
+
+impl Options {
+    /// Readable only by the current user.
+    pub fn private() -> Options {
+        Options { mode: Some(0o600), ..Options::default() }
+    }
+}
+
+/// Write `contents` to `path` so that readers see either the old file
+/// or the whole new one, never a partial write. The contents are
+/// written to a temporary file in the same directory, synced, and
+/// renamed over `path`.
+pub fn write_atomic(path: &Path, contents: &[u8], options: &Options) -> io::Result<()> {
+    let existing = std::fs::metadata(path).ok();
+    let mode = options.mode
+        .or_else(|| existing.as_ref().map(|metadata| metadata.mode() & 0o7777))
+        .unwrap_or(0o644);
+    let (file, temporary) = create_temporary(path)?;
+    let result = write_temporary(file, contents, mode, options.owner, existing.as_ref())
+        .and_then(|()| {
+            if options.backup && existing.is_some() {
+                backup(path)?;
+            }
+            std::fs::rename(&temporary, path)
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    result?;
+    // The rename is only durable once the directory is synced.
+    let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    File::open(directory)?.sync_all()
+}
+
+fn write_temporary(mut file: File, contents: &[u8], mode: u32, owner: Option<(u32, u32)>, existing: Option<&std::fs::Metadata>) -> io::Result<()> {
+    file.write_all(contents)?;
+    match owner {
+        Some(owner) => chown(&file, owner)?,
+        // Keeping the owner of the file being replaced needs
+        // privileges we may not have, so it is best effort.
+        None => if let Some(existing) = existing {
+            let created = file.metadata()?;
+            if (existing.uid(), existing.gid()) != (created.uid(), created.gid()) {
+                let _ = chown(&file, (existing.uid(), existing.gid()));
+            }
+        },
+    }
+    // Set after chown, which can clear setuid and setgid bits, and
+    // exactly, rather than masked by the umask.
+    file.set_permissions(Permissions::from_mode(mode))?;
+    file.sync_all()
+}
+
+fn chown(file: &File, (uid, gid): (u32, u32)) -> io::Result<()> {
+    if unsafe { libc::fchown(file.as_raw_fd(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A new hidden file next to `path`, so the rename stays on one
+/// filesystem. The name is random and the file must not already
+/// exist, so a file or symlink planted in the directory is never
+/// written through.
+fn create_temporary(path: &Path) -> io::Result<(File, PathBuf)> {
+    temporary(path, |temporary| OpenOptions::new().write(true).create_new(true).mode(0o600).open(temporary))
+}
+
+/// Keep the file at `path` as `<path>.bak`. The existing file is hard
+/// linked to a temporary name and renamed over the backup, so the
+/// backup is never partial and a symlink planted at `<path>.bak` is
+/// replaced rather than written through.
+fn backup(path: &Path) -> io::Result<()> {
+    let ((), temporary) = temporary(path, |temporary| std::fs::hard_link(path, temporary))?;
+    let result = std::fs::rename(&temporary, backup_path(path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Create something at a new random name next to `path` with
+/// `create`, which must fail if the name already exists.
+fn temporary<T, F: Fn(&Path) -> io::Result<T>>(path: &Path, create: F) -> io::Result<(T, PathBuf)> {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let random = SystemRandom::new();
+    let mut attempts = 0;
+    loop {
+        let mut bytes = [0u8; 8];
+        random.fill(&mut bytes).map_err(|_e| io::Error::new(ErrorKind::Other, "could not generate a temporary file name"))?;
+        let suffix = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let temporary = path.with_file_name(format!(".{}.tmp-{}", name, suffix));
+        match create(&temporary) {
+            Ok(created) => return Ok((created, temporary)),
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists && attempts < 10 => attempts += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!("{}.bak", name))
+}
+
+/// Parse an owner such as `root`, `root:ssh` or `0:0` to a uid and
+/// gid, without a group the user's primary group is used.
+pub fn parse_owner(owner: &str) -> Result<(u32, u32), String> {
+    let mut parts = owner.splitn(2, ':');
+    let user = parts.next().unwrap_or_default();
+    let (uid, primary) = lookup_user(user).ok_or_else(|| format!("'{}' is not a known user.", user))?;
+    let gid = match parts.next() {
+        Some(group) => lookup_group(group).ok_or_else(|| format!("'{}' is not a known group.", group))?,
+        None => primary.ok_or_else(|| format!("User '{}' has no primary group, give one as USER:GROUP.", user))?,
+    };
+    Ok((uid, gid))
+}
+
+/// The uid and primary gid of `user`, by name or number, a number
+/// without a passwd entry has no primary group.
+fn lookup_user(user: &str) -> Option<(u32, Option<u32>)> {
+    let name = CString::new(user).ok()?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !passwd.is_null() {
+        return Some(unsafe { ((*passwd).pw_uid, Some((*passwd).pw_gid)) });
+    }
+    let uid = user.parse::<u32>().ok()?;
+    let passwd = unsafe { libc::getpwuid(uid) };
+    if passwd.is_null() { Some((uid, None)) } else { Some((uid, Some(unsafe { (*passwd).pw_gid }))) }
+}
+
+fn lookup_group(group: &str) -> Option<u32> {
+    let name = CString::new(group).ok()?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if !entry.is_null() {
+        return Some(unsafe { (*entry).gr_gid });
+    }
+    group.parse::<u32>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).expect("File should exist.").mode() & 0o7777
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let directory = std::env::temp_dir().join(format!("smith-file-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).expect("Directory should be created.");
+        let path = directory.join("keys.pub");
+
+        write_atomic(&path, b"first\n", &Options::default()).expect("File should be written.");
+        assert_eq!(std::fs::read_to_string(&path).expect("File should be readable."), "first\n");
+        assert_eq!(mode(&path), 0o644);
+
+        std::fs::set_permissions(&path, Permissions::from_mode(0o640)).expect("Mode should be set.");
+        write_atomic(&path, b"second\n", &Options { backup: true, ..Options::default() }).expect("File should be replaced.");
+        assert_eq!(std::fs::read_to_string(&path).expect("File should be readable."), "second\n");
+        assert_eq!(mode(&path), 0o640);
+        assert_eq!(std::fs::read_to_string(backup_path(&path)).expect("Backup should be readable."), "first\n");
+        assert_eq!(mode(&backup_path(&path)), 0o640);
+
+        let target = directory.join("target");
+        std::fs::write(&target, "target\n").expect("Target should be written.");
+        std::fs::remove_file(backup_path(&path)).expect("Backup should be removed.");
+        std::os::unix::fs::symlink(&target, backup_path(&path)).expect("Symlink should be created.");
+        write_atomic(&path, b"second\n", &Options { backup: true, ..Options::default() }).expect("File should be replaced.");
+        assert_eq!(std::fs::read_to_string(&target).expect("Target should be readable."), "target\n");
+        assert!(!std::fs::symlink_metadata(backup_path(&path)).expect("Backup should exist.").file_type().is_symlink());
+        assert_eq!(std::fs::read_to_string(backup_path(&path)).expect("Backup should be readable."), "second\n");
+        std::fs::remove_file(&target).expect("Target should be removed.");
+
+        write_atomic(&path, b"third\n", &Options::private()).expect("File should be replaced.");
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(std::fs::read_dir(&directory).expect("Directory should be readable.").count(), 2);
+
+        let (_first, first) = create_temporary(&path).expect("Temporary should be created.");
+        let (_second, second) = create_temporary(&path).expect("Temporary should be created.");
+        assert_ne!(first, second);
+
+        std::fs::remove_dir_all(&directory).expect("Directory should be removed.");
+    }
+
+    #[test]
+    fn test_parse_owner() {
+        assert_eq!(parse_owner("0:0"), Ok((0, 0)));
+        assert_eq!(parse_owner("root"), Ok((0, 0)));
+        assert_eq!(parse_owner("root:0"), Ok((0, 0)));
+        assert!(parse_owner("no-such-user-smith").is_err());
+        assert!(parse_owner("root:no-such-group-smith").is_err());
+    }
+}
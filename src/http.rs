@@ -0,0 +1,250 @@
+use reqwest::{Certificate, Identity, Proxy, Url};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How to reach smith, shared by every request a program makes so
+/// they all see the same network configuration.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Settings {
+    pub connect_timeout: Duration,
+    /// Limit on a whole request, including reading the response.
+    pub timeout: Duration,
+    pub proxies: Proxies,
+    /// PEM certificates to trust in addition to the system roots,
+    /// e.g. for a TLS-intercepting proxy.
+    pub ca_bundle: Option<PathBuf>,
+    /// A PKCS#12 client certificate and key, with its password.
+    pub identity: Option<(PathBuf, String)>,
+}
+
+/// Proxies by the scheme of the request url, `all` is used where
+/// there is no scheme specific proxy. Hosts matching `no_proxy` are
+/// connected to directly.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Proxies {
+    pub http: Option<Url>,
+    pub https: Option<Url>,
+    pub all: Option<Url>,
+    pub no_proxy: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnsupportedProxyError(String),
+    CaBundleError(PathBuf, std::io::Error),
+    CertificateError(PathBuf, reqwest::Error),
+    IdentityError(PathBuf, std::io::Error),
+    InvalidIdentityError(PathBuf, reqwest::Error),
+    ClientError(reqwest::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnsupportedProxyError(proxy) =>
+              write!(f, "Proxy '{}' is not supported, it should be an http, https or socks5 url.", proxy),
+            Error::CaBundleError(path, _) =>
+              write!(f, "CA bundle could not be read, check it exists, tried: {:?}", path),
+            Error::CertificateError(path, _) =>
+              write!(f, "CA bundle is not valid, it should contain PEM certificates, tried: {:?}", path),
+            Error::IdentityError(path, _) =>
+              write!(f, "Client certificate could not be read, check it exists, tried: {:?}", path),
+            Error::InvalidIdentityError(path, _) =>
+              write!(f, "Client certificate is not valid, it should be a PKCS#12 file, check its password, tried: {:?}", path),
+            Error::ClientError(_) =>
+              write!(f, "HTTP client could not be configured, check your TLS configuration."),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            proxies: Proxies::default(),
+            ca_bundle: None,
+            identity: None,
+        }
+    }
+}
+
+impl Settings {
+    pub fn client(&self) -> Result<reqwest::Client, Error> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout);
+        if let Some(proxy) = self.proxy() {
+            builder = builder.proxy(proxy);
+        }
+        for certificate in self.root_certificates()? {
+            builder = builder.add_root_certificate(certificate);
+        }
+        if let Some(identity) = self.identity()? {
+            builder = builder.identity(identity);
+        }
+        builder.build().map_err(|e| Error::ClientError(e))
+    }
+
+    /// A client for `AsyncApi`, configured the same way.
+    #[cfg(feature = "async")]
+    pub fn async_client(&self) -> Result<reqwest::r#async::Client, Error> {
+        let mut builder = reqwest::r#async::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout);
+        if let Some(proxy) = self.proxy() {
+            builder = builder.proxy(proxy);
+        }
+        for certificate in self.root_certificates()? {
+            builder = builder.add_root_certificate(certificate);
+        }
+        if let Some(identity) = self.identity()? {
+            builder = builder.identity(identity);
+        }
+        builder.build().map_err(|e| Error::ClientError(e))
+    }
+
+    fn proxy(&self) -> Option<Proxy> {
+        if !self.proxies.is_configured() {
+            return None;
+        }
+        let proxies = self.proxies.clone();
+        Some(Proxy::custom(move |url| proxies.proxy_for(url)))
+    }
+
+    fn root_certificates(&self) -> Result<Vec<Certificate>, Error> {
+        let path = match self.ca_bundle {
+            Some(ref path) => path,
+            None => return Ok(vec![]),
+        };
+        let bundle = std::fs::read(path).map_err(|e| Error::CaBundleError(path.clone(), e))?;
+        certificates(&bundle)
+            .iter()
+            .map(|pem| Certificate::from_pem(pem.as_bytes()).map_err(|e| Error::CertificateError(path.clone(), e)))
+            .collect()
+    }
+
+    fn identity(&self) -> Result<Option<Identity>, Error> {
+        let (path, password) = match self.identity {
+            Some((ref path, ref password)) => (path, password),
+            None => return Ok(None),
+        };
+        let der = std::fs::read(path).map_err(|e| Error::IdentityError(path.clone(), e))?;
+        Identity::from_pkcs12_der(&der, password).map(Some).map_err(|e| Error::InvalidIdentityError(path.clone(), e))
+    }
+}
+
+impl Proxies {
+    /// Proxies from the conventional `HTTPS_PROXY`, `HTTP_PROXY`,
+    /// `ALL_PROXY` and `NO_PROXY` variables, upper or lower case.
+    pub fn from_env() -> Result<Proxies, Error> {
+        Ok(Proxies {
+            http: proxy_from_env("HTTP_PROXY")?,
+            https: proxy_from_env("HTTPS_PROXY")?,
+            all: proxy_from_env("ALL_PROXY")?,
+            no_proxy: env("NO_PROXY")
+                .map(|hosts| hosts.split(',').map(|host| host.trim().to_lowercase()).filter(|host| !host.is_empty()).collect())
+                .unwrap_or_default(),
+        })
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.http.is_some() || self.https.is_some() || self.all.is_some()
+    }
+
+    pub fn proxy_for(&self, url: &Url) -> Option<Url> {
+        let host = url.host_str().unwrap_or("").to_lowercase();
+        if self.no_proxy.iter().any(|pattern| matches_no_proxy(&host, pattern)) {
+            return None;
+        }
+        let proxy = match url.scheme() {
+            "https" => self.https.as_ref(),
+            "http" => self.http.as_ref(),
+            _ => None,
+        };
+        proxy.or(self.all.as_ref()).cloned()
+    }
+}
+
+pub fn parse_proxy(proxy: &str) -> Result<Url, Error> {
+    // Like curl, a proxy without a scheme is an http proxy.
+    let proxy = if proxy.contains("://") { proxy.to_string() } else { format!("http://{}", proxy) };
+    match Url::parse(&proxy) {
+        Ok(ref url) if ["http", "https", "socks5", "socks5h"].contains(&url.scheme()) => Ok(url.clone()),
+        _ => Err(Error::UnsupportedProxyError(proxy)),
+    }
+}
+
+fn proxy_from_env(name: &str) -> Result<Option<Url>, Error> {
+    env(name).map(|proxy| parse_proxy(&proxy)).transpose()
+}
+
+/// A variable by its upper or lower case name, empty is unset.
+fn env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .or_else(|_| std::env::var(name.to_lowercase()))
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+/// `NO_PROXY` entries are hosts, matching themselves and their
+/// subdomains, a leading `.` is ignored and `*` matches everything.
+fn matches_no_proxy(host: &str, pattern: &str) -> bool {
+    let pattern = pattern.trim_start_matches('.');
+    pattern == "*" || host == pattern || host.ends_with(&format!(".{}", pattern))
+}
+
+/// The PEM certificates in a bundle.
+fn certificates(bundle: &[u8]) -> Vec<String> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    String::from_utf8_lossy(bundle)
+        .split(END)
+        .filter_map(|block| block.find(BEGIN).map(|start| format!("{}{}\n", &block[start..], END)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_for() {
+        let proxies = Proxies {
+            http: None,
+            https: Some(parse_proxy("proxy.example.com:3128").expect("Proxy should parse.")),
+            all: Some(parse_proxy("socks5://127.0.0.1:1080").expect("Proxy should parse.")),
+            no_proxy: vec!["localhost".to_string(), ".internal.example.com".to_string()],
+        };
+        let proxy = |url: &str| proxies.proxy_for(&Url::parse(url).expect("Url should parse.")).map(|proxy| proxy.to_string());
+        assert_eq!(proxy("https://api.smith.st/issue"), Some("http://proxy.example.com:3128/".to_string()));
+        assert_eq!(proxy("http://api.smith.st/issue"), Some("socks5://127.0.0.1:1080/".to_string()));
+        assert_eq!(proxy("http://localhost:8000/issue"), None);
+        assert_eq!(proxy("https://smith.internal.example.com/issue"), None);
+        assert_eq!(proxy("https://notinternal.example.com/issue"), Some("http://proxy.example.com:3128/".to_string()));
+    }
+
+    #[test]
+    fn test_parse_proxy() {
+        assert!(parse_proxy("https://proxy.example.com").is_ok());
+        assert!(parse_proxy("socks5h://proxy.example.com:1080").is_ok());
+        assert!(parse_proxy("socks4://proxy.example.com:1080").is_err());
+    }
+
+    #[test]
+    fn test_certificates() {
+        let bundle = b"# first\n-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----\n";
+        assert_eq!(certificates(bundle), vec![
+            "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n".to_string(),
+            "-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----\n".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_client() {
+        assert!(Settings::default().client().is_ok());
+        let settings = Settings { ca_bundle: Some(PathBuf::from("test/data/missing.pem")), ..Settings::default() };
+        assert!(match settings.client() { Err(Error::CaBundleError(_, _)) => true, _ => false });
+    }
+}
@@ -0,0 +1,197 @@
+use biscuit::jwa;
+use biscuit::jwk::JWK;
+use ring::rand::SystemRandom;
+use ring::signature::{self, EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair};
+use serde::Serialize;
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum Algorithm {
+    RS256,
+    ES256,
+    ES384,
+    EdDSA,
+}
+
+pub enum SigningKey {
+    Rsa(RsaKeyPair),
+    Ecdsa(Algorithm, EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    SignError,
+    EncodeError(serde_json::Error),
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub alg: Algorithm,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+}
+
+/// The public half of a signing key, as embedded in a JWS header.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "kty")]
+pub enum PublicJwk {
+    RSA { e: String, n: String },
+    EC { crv: String, x: String, y: String },
+    OKP { crv: String, x: String },
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum OctetKeyPairType {
+    #[serde(rename = "OKP")]
+    OctetKeyPair,
+}
+
+/// An octet key pair JWK (RFC 8037), biscuit only understands RSA,
+/// EC and symmetric keys so these are parsed separately.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OctetKeyPair<A> {
+    pub kty: OctetKeyPairType,
+    pub crv: String,
+    pub x: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub d: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    #[serde(flatten)]
+    pub additional: A,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Jwk<A> {
+    Standard(JWK<A>),
+    OctetKeyPair(OctetKeyPair<A>),
+}
+
+impl<A> Jwk<A> {
+    pub fn additional(&self) -> &A {
+        match self {
+            Jwk::Standard(jwk) => &jwk.additional,
+            Jwk::OctetKeyPair(okp) => &okp.additional,
+        }
+    }
+
+    pub fn key_id(&self) -> Option<String> {
+        match self {
+            Jwk::Standard(jwk) => jwk.common.key_id.clone(),
+            Jwk::OctetKeyPair(okp) => okp.kid.clone(),
+        }
+    }
+
+    /// The signature algorithm named by `alg`, if any.
+    pub fn algorithm(&self) -> Option<Algorithm> {
+        match self {
+            Jwk::Standard(jwk) => match jwk.common.algorithm {
+                Some(jwa::Algorithm::Signature(jwa::SignatureAlgorithm::RS256)) => Some(Algorithm::RS256),
+                Some(jwa::Algorithm::Signature(jwa::SignatureAlgorithm::ES256)) => Some(Algorithm::ES256),
+                Some(jwa::Algorithm::Signature(jwa::SignatureAlgorithm::ES384)) => Some(Algorithm::ES384),
+                _ => None,
+            },
+            Jwk::OctetKeyPair(okp) => match okp.alg.as_ref().map(|alg| &alg[..]) {
+                Some("EdDSA") => Some(Algorithm::EdDSA),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::RS256 => "RS256",
+            Algorithm::ES256 => "ES256",
+            Algorithm::ES384 => "ES384",
+            Algorithm::EdDSA => "EdDSA",
+        }
+    }
+}
+
+impl SigningKey {
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            SigningKey::Rsa(_) => Algorithm::RS256,
+            SigningKey::Ecdsa(algorithm, _) => *algorithm,
+            SigningKey::Ed25519(_) => Algorithm::EdDSA,
+        }
+    }
+
+    pub fn public_jwk(&self) -> Result<PublicJwk, Error> {
+        let encode = |bytes: &[u8]| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+        match self {
+            SigningKey::Rsa(key) => {
+                // https://tools.ietf.org/html/rfc3447#appendix-A.1.1
+                let (n, e) = yasna::parse_der(key.public_key().as_ref(), |reader| {
+                    reader.read_sequence(|reader| {
+                        let n = reader.next().read_biguint()?;
+                        let e = reader.next().read_biguint()?;
+                        Ok((n, e))
+                    })
+                }).map_err(|_e| Error::SignError)?;
+                Ok(PublicJwk::RSA { e: encode(&e.to_bytes_be()), n: encode(&n.to_bytes_be()) })
+            },
+            SigningKey::Ecdsa(algorithm, key) => {
+                // Uncompressed point, 04 || x || y.
+                let point = &key.public_key().as_ref()[1..];
+                let (x, y) = point.split_at(point.len() / 2);
+                let crv = match algorithm {
+                    Algorithm::ES384 => "P-384",
+                    _ => "P-256",
+                };
+                Ok(PublicJwk::EC { crv: crv.to_string(), x: encode(x), y: encode(y) })
+            },
+            SigningKey::Ed25519(key) => {
+                Ok(PublicJwk::OKP { crv: "Ed25519".to_string(), x: encode(key.public_key().as_ref()) })
+            },
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            SigningKey::Rsa(key) => {
+                let mut signature = vec![0; key.public_modulus_len()];
+                key.sign(&signature::RSA_PKCS1_SHA256, &SystemRandom::new(), message, &mut signature)
+                    .map_err(|_e| Error::SignError)?;
+                Ok(signature)
+            },
+            SigningKey::Ecdsa(_, key) => {
+                let signature = key.sign(&SystemRandom::new(), untrusted::Input::from(message))
+                    .map_err(|_e| Error::SignError)?;
+                Ok(signature.as_ref().to_vec())
+            },
+            SigningKey::Ed25519(key) => {
+                Ok(key.sign(message).as_ref().to_vec())
+            },
+        }
+    }
+}
+
+/// The RFC 7638 thumbprint of `jwk`, members in lexicographic order.
+pub fn thumbprint(jwk: &PublicJwk) -> String {
+    let canonical = match jwk {
+        PublicJwk::RSA { e, n } => format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, e, n),
+        PublicJwk::EC { crv, x, y } => format!(r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#, crv, x, y),
+        PublicJwk::OKP { crv, x } => format!(r#"{{"crv":"{}","kty":"OKP","x":"{}"}}"#, crv, x),
+    };
+    let hash = ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes());
+    base64::encode_config(hash.as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Produce a compact serialised JWS of `claims`.
+pub fn encode<H: Serialize, C: Serialize>(key: &SigningKey, header: &H, claims: &C) -> Result<String, Error> {
+    let header = serde_json::to_vec(header).map_err(|e| Error::EncodeError(e))?;
+    let claims = serde_json::to_vec(claims).map_err(|e| Error::EncodeError(e))?;
+    let input = format!(
+        "{}.{}",
+        base64::encode_config(&header, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(&claims, base64::URL_SAFE_NO_PAD),
+    );
+    let signature = key.sign(input.as_bytes())?;
+    Ok(format!("{}.{}", input, base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)))
+}
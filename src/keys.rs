@@ -2,6 +2,7 @@ use crate::codec;
 
 use openssl::rsa::Rsa;
 use openssl::pkey::Private;
+use ring::digest;
 
 use std::io::Cursor;
 
@@ -13,3 +14,24 @@ pub fn encode_ssh(key: &Rsa<Private>, comment: &str) -> String {
     let encoded = base64::encode(&buffer.into_inner());
     format!("ssh-rsa {} {}", encoded, comment)
 }
+
+/// The SHA256 fingerprint of an encoded public key, as `ssh-keygen -l`
+/// shows it.
+pub fn fingerprint(key: &str) -> Option<String> {
+    let blob = key.split_whitespace().nth(1).and_then(|blob| base64::decode(blob).ok())?;
+    let hash = base64::encode(digest::digest(&digest::SHA256, &blob).as_ref());
+    Some(format!("SHA256:{}", hash.trim_end_matches('=')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEgIpsX3r/ow5SALwmH85BTG2rkuWYxCPrhsdsrT2NcM ca";
+        assert_eq!(fingerprint(key), Some("SHA256:7qgTxe5Lc8JaFe+qPng0AdDUVlQSCUrAnfhEtnRNgJc".to_string()));
+        assert_eq!(fingerprint("ssh-ed25519"), None);
+        assert_eq!(fingerprint("ssh-ed25519 !!!"), None);
+    }
+}
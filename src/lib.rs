@@ -11,9 +11,18 @@ extern crate yasna;
 
 pub mod agent;
 pub mod api;
+#[cfg(feature = "async")]
+pub mod async_api;
+pub mod authority;
 pub mod codec;
 pub mod configuration;
 pub mod data;
+pub mod file;
+pub mod http;
+pub mod jws;
 pub mod keys;
 pub mod oauth2;
+pub mod pinning;
+pub mod retry;
+pub mod transport;
 pub mod version;
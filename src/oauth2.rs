@@ -1,12 +1,33 @@
-use biscuit::{ClaimsSet, RegisteredClaims, JWT, SingleOrMultiple, StringOrUri};
-use biscuit::jwa::SignatureAlgorithm;
-use biscuit::jws::{RegisteredHeader, Secret};
-use biscuit::jwk::{JWK, AlgorithmParameters, RSAKeyParameters};
-use reqwest::Client;
-use reqwest::header::ACCEPT;
-use ring::signature::RsaKeyPair;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use crate::jws::{self, Algorithm, Jwk, SigningKey};
+use crate::pinning::{PinError, Pinning};
+use crate::retry::{self, Attempt};
+use crate::transport::{self, Request, Response};
+
+#[cfg(feature = "async")]
+pub mod async_store;
+pub mod browser;
+pub mod cache;
+pub mod device;
+pub mod discovery;
+pub mod dpop;
+pub mod revocation;
+pub mod session;
+
+use self::cache::{CacheKey, TokenCache};
+use self::discovery::{Discovery, DiscoveryError};
+use self::session::Session;
+
+use biscuit::{ClaimsSet, RegisteredClaims, SingleOrMultiple, StringOrUri, Timestamp};
+use biscuit::jwk::{AlgorithmParameters, EllipticCurve, EllipticCurveKeyParameters, RSAKeyParameters};
+use reqwest::{Client, Url};
+use reqwest::header::HeaderValue;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, EcdsaKeyPair, Ed25519KeyPair, RsaKeyPair};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -19,6 +40,8 @@ pub struct AccessTokenResponse {
     pub access_token: String,
     pub expires_in: u64,
     pub token_type: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -35,163 +58,661 @@ pub struct AccessToken {
 #[derive(Debug, PartialEq, Clone)]
 pub struct AccessTokenState {
     pub token: AccessToken,
-    pub requested_at: Instant,
-    pub expires_in: Duration
+    pub expires_at: SystemTime,
 }
 
+/// A token store that can be shared between threads, concurrent
+/// callers needing a new token wait for a single refresh. Tokens are
+/// held per set of scopes, see `scope_set`.
 pub struct Store {
     pub client: Client,
-    pub state: Option<AccessTokenState>,
+    pub state: Mutex<HashMap<Vec<String>, AccessTokenState>>,
     pub configuration: Configuration,
+    refreshing: Mutex<()>,
+    endpoints: Mutex<Option<Endpoints>>,
+    /// The latest `DPoP-Nonce` from the token endpoint.
+    dpop_nonce: Mutex<Option<String>>,
+}
+
+/// Refreshes a store's token on a background thread ahead of expiry,
+/// stopping when dropped or when the store is.
+pub struct Refresher {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// A smith issued JWK, exchanged for tokens with the jwt-bearer grant.
+#[derive(Clone)]
+pub struct AssertionCredentials {
+    pub key: Arc<SigningKey>,
+    pub key_id: Option<String>,
+    pub issuer: String,
+}
+
+/// Where to read a third-party token for the token exchange grant,
+/// it is re-read on each exchange as CI systems may rotate it.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SubjectToken {
+    Env(String),
+    File(PathBuf),
+}
+
+#[derive(Clone)]
+pub enum Credentials {
+    Assertion(AssertionCredentials),
+    /// Tokens obtained by an interactive login, persisted at this path.
+    Session(PathBuf),
+    /// A third-party JWT, e.g. a CI OIDC ID token, exchanged for tokens.
+    TokenExchange(SubjectToken),
+}
+
+/// The endpoints a store uses, and whether its tokens are DPoP bound,
+/// resolved on first use as they may come from discovery.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Endpoints {
+    pub token: String,
+    pub device: String,
+    pub authorization: String,
+    pub revocation: Option<String>,
+    pub dpop: bool,
 }
 
 #[derive(Clone)]
 pub struct Configuration {
-    pub key: Arc<Secret>,
+    pub credentials: Credentials,
+    pub client_id: String,
+    /// The endpoints used unless discovery finds others.
     pub endpoint: String,
-    pub issuer: String,
+    pub device_endpoint: String,
+    pub authorization_endpoint: String,
+    pub revocation_endpoint: Option<String>,
     pub audience: String,
     pub scopes: Vec<String>,
+    pub assertion_lifetime: Duration,
+    /// Tokens are refreshed when they have less than this left.
+    pub refresh_margin: Duration,
+    /// Directory for the on-disk token cache, if enabled.
+    pub cache: Option<PathBuf>,
+    /// Bind tokens to the credential key with DPoP proofs, None to
+    /// bind them when the discovered metadata supports the key.
+    pub dpop: Option<bool>,
+    /// Where to discover the endpoints from, if enabled.
+    pub discovery: Option<Discovery>,
+    /// How token requests that fail transiently are retried.
+    pub retry: retry::Policy,
+    /// Public key pins for the smith hosts, if configured.
+    pub pins: Option<Arc<Pinning>>,
 }
 
 impl Configuration {
-    pub fn initialise(&self) -> Store {
-        Store::new(reqwest::Client::new(), self.clone())
-    }
-
     pub fn initialise_with(&self, client: reqwest::Client) -> Store {
         Store::new(client, self.clone())
     }
 
-    pub fn build_secret<A>(jwk: &JWK<A>) -> Result<Arc<Secret>, KeyError> {
-        match &jwk.algorithm {
-            AlgorithmParameters::RSA(rsa) => {
-                let secret = build_secret(&rsa)?;
-                Ok(Arc::new(secret))
-             },
-            AlgorithmParameters::EllipticCurve(_ec) => Err(KeyError::UnsupportedKeyError),
-            AlgorithmParameters::OctectKey { key_type: _, value: _ } => Err(KeyError::UnsupportedKeyError),
+    /// The endpoints to use, discovered with `client` if enabled.
+    /// Metadata that can't be fetched means the configured endpoints,
+    /// unless discovery is required.
+    pub fn endpoints(&self, client: &reqwest::Client) -> Result<Endpoints, GrantError> {
+        let configured = Endpoints {
+            token: self.endpoint.clone(),
+            device: self.device_endpoint.clone(),
+            authorization: self.authorization_endpoint.clone(),
+            revocation: self.revocation_endpoint.clone(),
+            dpop: self.dpop.unwrap_or(false),
+        };
+        let discovery = match self.discovery {
+            Some(ref discovery) => discovery,
+            None => return Ok(configured),
+        };
+        let metadata = match discovery.cache.discover(client, &self.pins, &discovery.issuer) {
+            Ok(metadata) => metadata,
+            Err(e) if discovery.required => return Err(GrantError::DiscoveryError(e)),
+            Err(_) => return Ok(configured),
+        };
+        let algorithm = match self.credentials {
+            Credentials::Assertion(ref assertion) => Some(assertion.key.algorithm().name()),
+            _ => None,
+        };
+        if let Some(algorithm) = algorithm {
+            if !metadata.supports_assertion(algorithm) {
+                return Err(GrantError::UnsupportedAlgorithmError(algorithm.to_string(), discovery.issuer.clone()));
+            }
+        }
+        Ok(Endpoints {
+            token: if discovery.keep_token_endpoint { configured.token } else { metadata.token_endpoint.clone() },
+            device: metadata.device_authorization_endpoint.clone().unwrap_or(configured.device),
+            authorization: metadata.authorization_endpoint.clone().unwrap_or(configured.authorization),
+            revocation: metadata.revocation_endpoint.clone(),
+            dpop: self.dpop.unwrap_or_else(|| algorithm.map(|algorithm| metadata.supports_dpop(algorithm)).unwrap_or(false)),
+        })
+    }
+
+    /// A jwt-bearer assertion for `scopes`, signed with the credentials.
+    pub fn sign_for(&self, scopes: &[String]) -> Result<String, GrantError> {
+        let credentials = match &self.credentials {
+            Credentials::Assertion(assertion) => assertion,
+            _ => return Err(GrantError::UnsupportedCredentialsError),
+        };
+        let issued_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| GrantError::ClockError(e))?
+            .as_secs() as i64;
+        let expiry = issued_at + self.assertion_lifetime.as_secs() as i64;
+        let claims = ClaimsSet::<PrivateClaims> {
+            registered: RegisteredClaims {
+                issuer: Some(StringOrUri::String(credentials.issuer.clone())),
+                subject: None,
+                audience: Some(SingleOrMultiple::Single(StringOrUri::String(self.audience.clone()))),
+                expiry: Some(Timestamp::from(expiry)),
+                not_before: Some(Timestamp::from(issued_at)),
+                issued_at: Some(Timestamp::from(issued_at)),
+                id: Some(random(16)?),
+            },
+            private: PrivateClaims {
+                scope: scopes.join(" "),
+            },
+        };
+        let header = jws::Header {
+            alg: credentials.key.algorithm(),
+            kid: credentials.key_id.clone(),
+        };
+        let assertion = jws::encode(&credentials.key, &header, &claims)
+            .map_err(|e| GrantError::JwtSignError(e))?;
+        Ok(assertion)
+    }
+
+    /// Parameters to exchange `subject_token` for a token for `scopes`.
+    pub fn token_exchange_parameters(&self, subject_token: &str, scopes: &[String]) -> Vec<(&'static str, String)> {
+        vec![
+            ("grant_type", "urn:ietf:params:oauth:grant-type:token-exchange".to_string()),
+            ("subject_token", subject_token.to_string()),
+            ("subject_token_type", "urn:ietf:params:oauth:token-type:jwt".to_string()),
+            ("requested_token_type", "urn:ietf:params:oauth:token-type:access_token".to_string()),
+            ("audience", self.audience.clone()),
+            ("scope", scopes.join(" ")),
+            ("client_id", self.client_id.clone()),
+        ]
+    }
+
+    pub fn build_secret<A>(jwk: &Jwk<A>) -> Result<Arc<SigningKey>, KeyError> {
+        let key = match jwk {
+            Jwk::Standard(jwk) => match &jwk.algorithm {
+                AlgorithmParameters::RSA(rsa) => build_rsa_secret(&rsa)?,
+                AlgorithmParameters::EllipticCurve(ec) => build_ec_secret(&ec)?,
+                AlgorithmParameters::OctectKey { key_type: _, value: _ } => return Err(KeyError::UnsupportedKeyError),
+            },
+            Jwk::OctetKeyPair(okp) => build_okp_secret(&okp.crv, &okp.x, &okp.d)?,
+        };
+        match jwk.algorithm() {
+            Some(algorithm) if algorithm != key.algorithm() => Err(KeyError::InconsistentKeyError),
+            _ => Ok(Arc::new(key)),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Configuration {
+    /// Configuration for the mock server at `server`, with the test JWK.
+    pub(crate) fn for_tests(server: &str) -> Configuration {
+        Configuration::for_tests_with(server, Path::new("test/data/credentials.json"))
+    }
+
+    /// Configuration for the mock server at `server`, with the JWK in
+    /// `credentials`.
+    pub(crate) fn for_tests_with(server: &str, credentials: &Path) -> Configuration {
+        let contents = std::fs::read_to_string(credentials).expect("Should be able to read credentials file.");
+        let jwk: Jwk<crate::configuration::IdentityId> = serde_json::from_str(&contents).expect("Should be able to deserialise credentials file.");
+        Configuration {
+            credentials: Credentials::Assertion(AssertionCredentials {
+                key: Configuration::build_secret(&jwk).expect("Should be able to build signing secret."),
+                key_id: Some("mock".to_string()),
+                issuer: "me".to_string(),
+            }),
+            client_id: "mock".to_string(),
+            endpoint: format!("{}/oauth/token", server),
+            device_endpoint: format!("{}/oauth/device/code", server),
+            authorization_endpoint: format!("{}/oauth/authorize", server),
+            revocation_endpoint: Some(format!("{}/oauth/revoke", server)),
+            audience: "mock".to_string(),
+            scopes: vec!["scope".to_string()],
+            assertion_lifetime: Duration::from_secs(60),
+            refresh_margin: Duration::from_secs(60),
+            cache: None,
+            dpop: Some(false),
+            discovery: None,
+            retry: retry::Policy::default(),
+            pins: None,
         }
     }
 }
 
 #[derive(Debug)]
 pub enum GrantError {
-    JwtSignError(biscuit::errors::Error),
-    JwtEncodeError(biscuit::errors::Error),
+    JwtSignError(jws::Error),
+    ClockError(std::time::SystemTimeError),
+    RandomError,
     NetworkError(reqwest::Error),
     InvalidStatusCodeError(reqwest::StatusCode),
     AccessTokenError(AccessTokenError),
-    Json400ParseError(reqwest::Error),
-    Json200ParseError(reqwest::Error),
+    Json400ParseError(serde_json::Error),
+    Json200ParseError(serde_json::Error),
+    SessionError(session::SessionError),
+    UnsupportedCredentialsError,
+    DeviceAuthorizationExpiredError,
+    LoginRequired,
+    InvalidEndpointError(String),
+    LoopbackError(std::io::Error),
+    StateMismatchError,
+    MissingCodeError,
+    LoginTimeoutError,
+    SubjectTokenError(std::io::Error),
+    SubjectTokenMissingError(String),
+    RevocationUnsupportedError,
+    UnavailableError(reqwest::StatusCode, Option<Duration>),
+    PinningError(PinError),
+    InvalidHeaderError(String),
+    DiscoveryError(DiscoveryError),
+    UnsupportedAlgorithmError(String, String),
+    /// Work `AsyncStore` moved off the executor stopped without a
+    /// result.
+    CanceledError,
 }
 
 #[derive(Debug)]
 pub enum KeyError {
     UnsupportedKeyError,
     IncompleteKeyError,
+    InconsistentKeyError,
+}
+
+impl SubjectToken {
+    pub fn read(&self) -> Result<String, GrantError> {
+        let token = match self {
+            SubjectToken::Env(name) => std::env::var(name).unwrap_or_default(),
+            SubjectToken::File(path) => std::fs::read_to_string(path).map_err(|e| GrantError::SubjectTokenError(e))?,
+        };
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(GrantError::SubjectTokenMissingError(match self {
+                SubjectToken::Env(name) => name.clone(),
+                SubjectToken::File(path) => path.display().to_string(),
+            }));
+        }
+        Ok(token.to_string())
+    }
 }
 
 impl Store {
     pub fn new(client: reqwest::Client, configuration: Configuration) -> Store {
         Store {
             client: client,
-            state: None,
+            state: Mutex::new(HashMap::new()),
             configuration: configuration,
+            refreshing: Mutex::new(()),
+            endpoints: Mutex::new(None),
+            dpop_nonce: Mutex::new(None),
         }
     }
 
-    pub fn grant(&mut self) -> Result<AccessToken, GrantError> {
-        match self.local() {
-            Some(token) => Ok(token.clone()),
-            None => {
-                let state = self.refresh()?;
-                let token = state.token.clone();
-                self.state = Some(state);
-                Ok(token)
-            },
+    /// The endpoints, discovered by the first caller, others wait for
+    /// it rather than making requests of their own.
+    pub fn endpoints(&self) -> Result<Endpoints, GrantError> {
+        let mut endpoints = lock(&self.endpoints);
+        if let Some(ref endpoints) = *endpoints {
+            return Ok(endpoints.clone());
+        }
+        let resolved = self.configuration.endpoints(&self.client)?;
+        *endpoints = Some(resolved.clone());
+        Ok(resolved)
+    }
+
+    /// A token for the configured scopes.
+    pub fn grant(&self) -> Result<AccessToken, GrantError> {
+        self.grant_for(&self.configuration.scopes)
+    }
+
+    /// A token for just `scopes`.
+    pub fn grant_for(&self, scopes: &[String]) -> Result<AccessToken, GrantError> {
+        match self.local_for(scopes) {
+            Some(token) => Ok(token),
+            None => self.renew_for(scopes, false),
+        }
+    }
+
+    pub fn renew(&self, force: bool) -> Result<AccessToken, GrantError> {
+        self.renew_for(&self.configuration.scopes, force)
+    }
+
+    /// Obtain a new token, unless `force` is set a token refreshed by
+    /// another thread while waiting is used instead.
+    pub fn renew_for(&self, scopes: &[String], force: bool) -> Result<AccessToken, GrantError> {
+        let _refreshing = lock(&self.refreshing);
+        if !force {
+            if let Some(token) = self.local_for(scopes) {
+                return Ok(token);
+            }
         }
+        let state = match (&self.configuration.credentials, self.configuration.cache.clone()) {
+            (Credentials::Assertion(_), Some(directory)) => self.refresh_cached(&TokenCache::new(directory), scopes, force)?,
+            _ => self.refresh_for(scopes)?,
+        };
+        let token = state.token.clone();
+        lock(&self.state).insert(scope_set(scopes), state);
+        Ok(token)
     }
 
     pub fn local(&self) -> Option<AccessToken> {
-        self.state.as_ref().and_then(|state| {
-            let now = Instant::now();
-            if now.duration_since(state.requested_at) > state.expires_in {
-               None
-            } else {
+        self.local_for(&self.configuration.scopes)
+    }
+
+    pub fn local_for(&self, scopes: &[String]) -> Option<AccessToken> {
+        lock(&self.state).get(&scope_set(scopes)).and_then(|state| {
+            if cache::is_fresh(state, self.configuration.refresh_margin) {
                Some(state.token.clone())
+            } else {
+               None
             }
         })
     }
 
+    /// When the token for the configured scopes expires.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        lock(&self.state).get(&scope_set(&self.configuration.scopes)).map(|state| state.expires_at)
+    }
+
+    pub fn cache_key(&self) -> Result<CacheKey, GrantError> {
+        self.cache_key_for(&self.configuration.scopes)
+    }
+
+    pub fn cache_key_for(&self, scopes: &[String]) -> Result<CacheKey, GrantError> {
+        let endpoints = self.endpoints()?;
+        let identity = match &self.configuration.credentials {
+            Credentials::Assertion(assertion) => assertion.issuer.clone(),
+            Credentials::Session(path) => path.display().to_string(),
+            Credentials::TokenExchange(SubjectToken::Env(name)) => format!("env:{}", name),
+            Credentials::TokenExchange(SubjectToken::File(path)) => format!("file:{}", path.display()),
+        };
+        Ok(CacheKey {
+            endpoint: endpoints.token,
+            // DPoP bound tokens can't be used as bearer tokens, or vice versa.
+            identity: if endpoints.dpop { format!("{}+dpop", identity) } else { identity },
+            scopes: scopes.to_vec(),
+        })
+    }
+
+    /// The cache is best effort, a cache that can't be locked, read or
+    /// written just means a fresh grant.
+    fn refresh_cached(&self, cache: &TokenCache, scopes: &[String], force: bool) -> Result<AccessTokenState, GrantError> {
+        let key = self.cache_key_for(scopes)?;
+        let _lock = cache.lock(&key).ok();
+        if !force {
+            if let Ok(Some(state)) = cache.load(&key) {
+                if cache::is_fresh(&state, self.configuration.refresh_margin) {
+                    return Ok(state);
+                }
+            }
+        }
+        let state = self.refresh_for(scopes)?;
+        let _ = cache.store(&key, &state);
+        Ok(state)
+    }
+
     pub fn refresh(&self) -> Result<AccessTokenState, GrantError> {
-        let requested_at = Instant::now();
-        let assertion = self.sign()?;
-        let parameters = vec![
-            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer".to_string()),
-            ("assertion", assertion)
-        ];
+        self.refresh_for(&self.configuration.scopes)
+    }
+
+    /// A new token for `scopes`, sessions are limited to the scopes
+    /// they were logged in with. Network errors and unavailable token
+    /// endpoints are retried.
+    pub fn refresh_for(&self, scopes: &[String]) -> Result<AccessTokenState, GrantError> {
+        self.configuration.retry.run(|| self.refresh_once(scopes).map_err(attempt))
+    }
 
-        let mut response: reqwest::Response = self.client
-            .post(&self.configuration.endpoint)
-            .header(ACCEPT, "application/json")
-            .form(&parameters)
-            .send()
-            .map_err(|e| GrantError::NetworkError(e))?;
+    fn refresh_once(&self, scopes: &[String]) -> Result<AccessTokenState, GrantError> {
+        match &self.configuration.credentials {
+            Credentials::Assertion(_) => self.exchange_for(scopes),
+            Credentials::Session(path) => self.refresh_session(path),
+            Credentials::TokenExchange(subject) => {
+                let subject_token = subject.read()?;
+                self.exchange_token(&subject_token, scopes)
+            },
+        }
+    }
 
-        match response.status() {
+    // https://tools.ietf.org/html/rfc8693
+    pub fn exchange_token(&self, subject_token: &str, scopes: &[String]) -> Result<AccessTokenState, GrantError> {
+        let requested_at = SystemTime::now();
+        let response = self.token(&self.configuration.token_exchange_parameters(subject_token, scopes))?;
+        Ok(AccessTokenState {
+            token: AccessToken { value: response.access_token },
+            expires_at: requested_at + Duration::from_secs(response.expires_in),
+        })
+    }
+
+    /// Use the session's access token while it is fresh, otherwise its
+    /// refresh token, persisting any rotated refresh token.
+    fn refresh_session(&self, path: &Path) -> Result<AccessTokenState, GrantError> {
+        let _lock = Session::lock(path).map_err(|e| GrantError::SessionError(e))?;
+        let session = match Session::load(path).map_err(|e| GrantError::SessionError(e))? {
+            Some(session) => session,
+            None => return Err(GrantError::LoginRequired),
+        };
+        if cache::is_fresh(&session.state(), self.configuration.refresh_margin) {
+            return Ok(session.state());
+        }
+        let refresh_token = match session.refresh_token {
+            Some(ref refresh_token) => refresh_token.clone(),
+            None if session.state().expires_at > SystemTime::now() => return Ok(session.state()),
+            None => return Err(GrantError::LoginRequired),
+        };
+        let requested_at = SystemTime::now();
+        let response = match self.token(&[
+            ("grant_type", "refresh_token".to_string()),
+            ("refresh_token", refresh_token),
+            ("client_id", self.configuration.client_id.clone()),
+        ]) {
+            Ok(response) => response,
+            Err(GrantError::AccessTokenError(ref error)) if error.error == "invalid_grant" =>
+                return Err(GrantError::LoginRequired),
+            Err(e) => return Err(e),
+        };
+        let refreshed = session.refreshed(&response, requested_at);
+        refreshed.save(path).map_err(|e| GrantError::SessionError(e))?;
+        Ok(refreshed.state())
+    }
+
+    pub fn exchange(&self, assertion: &str) -> Result<AccessTokenState, GrantError> {
+        let requested_at = SystemTime::now();
+        let response = self.token(&jwt_bearer_parameters(assertion))?;
+        Ok(AccessTokenState {
+            token: AccessToken { value: response.access_token },
+            expires_at: requested_at + Duration::from_secs(response.expires_in),
+        })
+    }
+
+    /// Exchange a newly signed assertion for a token for `scopes`, a
+    /// retry signs another as the server may have used up the first.
+    pub fn exchange_for(&self, scopes: &[String]) -> Result<AccessTokenState, GrantError> {
+        let requested_at = SystemTime::now();
+        let response = self.token_with(|| Ok(jwt_bearer_parameters(&self.sign_for(scopes)?)))?;
+        Ok(AccessTokenState {
+            token: AccessToken { value: response.access_token },
+            expires_at: requested_at + Duration::from_secs(response.expires_in),
+        })
+    }
+
+    /// Make a token endpoint request, `parameters` determine the grant.
+    pub fn token(&self, parameters: &[(&str, String)]) -> Result<AccessTokenResponse, GrantError> {
+        self.token_with(|| Ok(parameters.to_vec()))
+    }
+
+    /// Make a token endpoint request with the parameters from
+    /// `parameters`, which is called again for a retry.
+    pub fn token_with<'a, F>(&self, parameters: F) -> Result<AccessTokenResponse, GrantError>
+        where F: Fn() -> Result<Vec<(&'a str, String)>, GrantError> {
+        match self.token_once(&parameters()?) {
+            // The nonce from this response is used on the retry.
+            Err(GrantError::AccessTokenError(ref error)) if error.error == "use_dpop_nonce" => self.token_once(&parameters()?),
+            result => result,
+        }
+    }
+
+    fn token_once(&self, parameters: &[(&str, String)]) -> Result<AccessTokenResponse, GrantError> {
+        let endpoint = self.endpoints()?.token;
+        let nonce = lock(&self.dpop_nonce).clone();
+        let proof = self.proof("POST", &endpoint, None, nonce)?;
+        let mut request = Request::form(url(&endpoint)?, parameters);
+        if let Some(proof) = proof {
+            let proof = HeaderValue::from_str(&proof).map_err(|_e| GrantError::InvalidHeaderError("DPoP".to_string()))?;
+            request.headers.insert("DPoP", proof);
+        }
+        let response = self.send(request)?;
+        if let Some(nonce) = response.headers.get("DPoP-Nonce").and_then(|nonce| nonce.to_str().ok()) {
+            *lock(&self.dpop_nonce) = Some(nonce.to_string());
+        }
+
+        match response.status {
             reqwest::StatusCode::OK => {
-                let response: AccessTokenResponse = response
-                    .json()
-                    .map_err(|e| GrantError::Json200ParseError(e))?;
-
-                Ok(AccessTokenState {
-                    token: AccessToken { value: response.access_token },
-                    requested_at: requested_at,
-                    expires_in: Duration::new(response.expires_in, 0),
-                })
+                serde_json::from_slice(&response.body)
+                    .map_err(|e| GrantError::Json200ParseError(e))
             },
             reqwest::StatusCode::BAD_REQUEST => {
-                let response: AccessTokenError = response
-                    .json()
+                let error: AccessTokenError = serde_json::from_slice(&response.body)
                     .map_err(|e| GrantError::Json400ParseError(e))?;
-                Err(GrantError::AccessTokenError(response))
+                Err(GrantError::AccessTokenError(error))
               },
+            s if retry::is_retryable(s) => {
+                Err(GrantError::UnavailableError(s, retry::retry_after(&response.headers)))
+            },
             s => {
                 Err(GrantError::InvalidStatusCodeError(s))
             },
         }
     }
 
+    /// Send `request`, through the pins if configured, so they are
+    /// checked on the connection that carries it.
+    pub(crate) fn send(&self, request: Request) -> Result<Response, GrantError> {
+        match self.configuration.pins {
+            Some(ref pins) => pins.send(request).map_err(|e| GrantError::PinningError(e)),
+            None => transport::send_with(&self.client, request).map_err(|e| GrantError::NetworkError(e)),
+        }
+    }
+
     pub fn sign(&self) -> Result<String, GrantError> {
-        let claims = ClaimsSet::<PrivateClaims> {
-            registered: RegisteredClaims {
-                issuer: Some(StringOrUri::String(self.configuration.issuer.clone())),
-                subject: None,
-                audience: Some(SingleOrMultiple::Single(StringOrUri::String(self.configuration.audience.clone()))),
-               ..Default::default()
-            },
-            private: PrivateClaims {
-                scope: self.configuration.scopes.join(" "),
-            },
-        };
-        let jwt: JWT<PrivateClaims, biscuit::Empty> = JWT::new_decoded(From::from(
-            RegisteredHeader {
-                algorithm: SignatureAlgorithm::RS256,
-               ..Default::default()
-            }),
-            claims.clone(),
-        );
-        let assertion = jwt.encode(&self.configuration.key)
-            .map_err(|e| GrantError::JwtSignError(e))?
-            .encoded()
-            .map_err(|e| GrantError::JwtEncodeError(e))?
-            .encode();
-        Ok(assertion)
+        self.sign_for(&self.configuration.scopes)
+    }
+
+    pub fn sign_for(&self, scopes: &[String]) -> Result<String, GrantError> {
+        self.configuration.sign_for(scopes)
+    }
+}
+
+impl Refresher {
+    /// Refresh `lead` before the current token expires, retrying
+    /// failed refreshes every `RETRY_INTERVAL`.
+    pub fn spawn(store: &Arc<Store>, lead: Duration) -> Refresher {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let signal = stopped.clone();
+        let store = Arc::downgrade(store);
+        let thread = thread::spawn(move || {
+            let mut wait = Duration::from_secs(0);
+            while !sleep(&signal, wait) {
+                let store = match store.upgrade() {
+                    Some(store) => store,
+                    None => return,
+                };
+                let force = store.expires_at().is_some();
+                wait = match store.renew(force) {
+                    Ok(_) => store.expires_at().map(|expires_at| until_refresh(expires_at, lead)).unwrap_or(RETRY_INTERVAL),
+                    Err(_) => RETRY_INTERVAL,
+                };
+            }
+        });
+        Refresher { stopped, thread: Some(thread) }
+    }
+}
+
+impl Drop for Refresher {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stopped;
+        *lock(stopped) = true;
+        condvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+fn jwt_bearer_parameters(assertion: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer".to_string()),
+        ("assertion", assertion.to_string()),
+    ]
+}
+
+/// Whether a failed grant is worth retrying.
+pub(crate) fn attempt(error: GrantError) -> Attempt<GrantError> {
+    let retry_after = match error {
+        GrantError::NetworkError(_) => None,
+        GrantError::PinningError(ref e) if e.is_transient() => None,
+        GrantError::UnavailableError(_, retry_after) => retry_after,
+        _ => return Attempt::Fail(error),
+    };
+    Attempt::Retry(error, retry_after)
+}
+
+pub(crate) fn url(endpoint: &str) -> Result<Url, GrantError> {
+    Url::parse(endpoint).map_err(|_e| GrantError::InvalidEndpointError(endpoint.to_string()))
+}
+
+/// Scopes in a canonical order, so the same set shares a token.
+pub(crate) fn scope_set(scopes: &[String]) -> Vec<String> {
+    let mut scopes = scopes.to_vec();
+    scopes.sort();
+    scopes.dedup();
+    scopes
+}
+
+/// How long to wait before refreshing a token, tokens that live for
+/// less than `lead` are refreshed half way through their life.
+fn until_refresh(expires_at: SystemTime, lead: Duration) -> Duration {
+    let remaining = expires_at.duration_since(SystemTime::now()).unwrap_or(Duration::from_secs(0));
+    let wait = if remaining > lead { remaining - lead } else { remaining / 2 };
+    std::cmp::max(wait, Duration::from_secs(1))
+}
+
+/// Sleep for `wait`, returning early with true if stopped.
+fn sleep(signal: &(Mutex<bool>, Condvar), wait: Duration) -> bool {
+    let (stopped, condvar) = signal;
+    let deadline = Instant::now() + wait;
+    let mut stopped = lock(stopped);
+    loop {
+        let now = Instant::now();
+        if *stopped || now >= deadline {
+            return *stopped;
+        }
+        stopped = condvar.wait_timeout(stopped, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
     }
 }
 
-fn build_secret(key: &RSAKeyParameters) -> Result<Secret, KeyError> {
+/// Locks are only held for simple assignments, so a poisoned lock
+/// still holds consistent data.
+fn lock<A>(mutex: &Mutex<A>) -> MutexGuard<A> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// `size` random bytes, url-safe encoded, used as the `jti` so each
+/// assertion can only be exchanged once and for login state.
+pub(crate) fn random(size: usize) -> Result<String, GrantError> {
+    let mut bytes = vec![0u8; size];
+    SystemRandom::new().fill(&mut bytes).map_err(|_e| GrantError::RandomError)?;
+    Ok(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+}
+
+fn build_rsa_secret(key: &RSAKeyParameters) -> Result<SigningKey, KeyError> {
     // https://tools.ietf.org/html/rfc3447#appendix-A.1.2
     let n = &key.n;
     let e = &key.e;
@@ -215,37 +736,429 @@ fn build_secret(key: &RSAKeyParameters) -> Result<Secret, KeyError> {
         });
     });
     let key = RsaKeyPair::from_der(untrusted::Input::from(&der)).map_err(|_e| KeyError::IncompleteKeyError)?;
-    Ok(Secret::RsaKeyPair(Arc::new(key)))
+    Ok(SigningKey::Rsa(key))
+}
+
+fn build_ec_secret(key: &EllipticCurveKeyParameters) -> Result<SigningKey, KeyError> {
+    let (algorithm, signing) = match key.curve {
+        EllipticCurve::P256 => (Algorithm::ES256, &signature::ECDSA_P256_SHA256_FIXED_SIGNING),
+        EllipticCurve::P384 => (Algorithm::ES384, &signature::ECDSA_P384_SHA384_FIXED_SIGNING),
+        _ => return Err(KeyError::UnsupportedKeyError),
+    };
+    let d = key.d.as_ref().ok_or(KeyError::IncompleteKeyError)?;
+    // https://tools.ietf.org/html/rfc5480#section-2.2, uncompressed point.
+    let mut public = vec![4];
+    public.extend_from_slice(&key.x);
+    public.extend_from_slice(&key.y);
+    let key = EcdsaKeyPair::from_private_key_and_public_key(signing, untrusted::Input::from(d), untrusted::Input::from(&public))
+        .map_err(|_e| KeyError::IncompleteKeyError)?;
+    Ok(SigningKey::Ecdsa(algorithm, key))
 }
 
+fn build_okp_secret(curve: &str, x: &str, d: &Option<String>) -> Result<SigningKey, KeyError> {
+    if curve != "Ed25519" {
+        return Err(KeyError::UnsupportedKeyError);
+    }
+    let d = d.as_ref().ok_or(KeyError::IncompleteKeyError)?;
+    let d = base64::decode_config(d, base64::URL_SAFE_NO_PAD).map_err(|_e| KeyError::IncompleteKeyError)?;
+    let x = base64::decode_config(x, base64::URL_SAFE_NO_PAD).map_err(|_e| KeyError::IncompleteKeyError)?;
+    let key = Ed25519KeyPair::from_seed_and_public_key(untrusted::Input::from(&d), untrusted::Input::from(&x))
+        .map_err(|_e| KeyError::IncompleteKeyError)?;
+    Ok(SigningKey::Ed25519(key))
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::configuration::IdentityId;
-    use std::fs::File;
-    use std::io::prelude::*;
     use std::path::Path;
 
-    fn build_secret_from_file(credentials: &Path) -> Arc<Secret> {
-        let mut file = File::open(credentials).expect("Credentials path should exist.");
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).expect("Should be able to read credentials file.");
-        let jwk: JWK<IdentityId> = serde_json::from_str(&contents).expect("Should be able to deserialise credentials file.");
-        Configuration::build_secret(&jwk).expect("Should be able to build signing secret.")
+    fn test_configuration() -> Configuration {
+        Configuration::for_tests(&server())
+    }
+
+    fn test_configuration_with(credentials: &Path) -> Configuration {
+        Configuration::for_tests_with(&server(), credentials)
+    }
+
+    fn server() -> String {
+        std::env::var("SERVER").unwrap_or("http://localhost:8000".to_string())
+    }
+
+    fn test_client() -> Client {
+        crate::http::Settings::default().client().expect("Client should build.")
+    }
+
+    fn initialise(configuration: &Configuration) -> Store {
+        configuration.initialise_with(test_client())
+    }
+
+    fn decode_part(assertion: &str, index: usize) -> serde_json::Value {
+        let parts = assertion.split('.').collect::<Vec<_>>();
+        assert_eq!(parts.len(), 3);
+        let part = base64::decode_config(parts[index], base64::URL_SAFE_NO_PAD).expect("Part should be base64.");
+        serde_json::from_slice(&part).expect("Part should be json.")
+    }
+
+    fn decode_claims(assertion: &str) -> serde_json::Value {
+        decode_part(assertion, 1)
     }
 
     #[test]
     fn test_oauth_token() {
+        let store = initialise(&test_configuration());
+        let token = store.grant().expect("Grant should succeed.");
+        assert_eq!(token, AccessToken { value: "mock".to_string() });
+    }
+
+    #[test]
+    fn test_device_authorization() {
+        let path = std::env::temp_dir().join(format!("smith-session-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut configuration = test_configuration();
+        configuration.credentials = Credentials::Session(path.clone());
+        let store = initialise(&configuration);
+        assert!(match store.grant() { Err(GrantError::LoginRequired) => true, _ => false });
+
+        let authorization = store.authorize_device().expect("Device authorization should succeed.");
         let server = std::env::var("SERVER").unwrap_or("http://localhost:8000".to_string());
-        let configuration = Configuration {
-            key: build_secret_from_file(Path::new("test/data/credentials.json")),
-            endpoint: format!("{}/oauth/token", server),
-            issuer: "me".to_string(),
-            audience: "mock".to_string(),
-            scopes: vec!["scope".to_string()],
-        };
-        let mut store = configuration.initialise();
+        let user_code = authorization.user_code.clone();
+        let approval = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(1500));
+            test_client()
+                .post(&format!("{}/device/approve", server))
+                .form(&[("user_code", user_code)])
+                .send()
+                .expect("Approval should succeed.");
+        });
+        let session = store.poll_device(&authorization).expect("Polling should succeed.");
+        approval.join().expect("Approval thread should succeed.");
+        assert!(session.refresh_token.as_ref().expect("Session should have a refresh token.").starts_with("mock-refresh"));
+
+        session.save(&path).expect("Session should save.");
         let token = store.grant().expect("Grant should succeed.");
         assert_eq!(token, AccessToken { value: "mock".to_string() });
     }
+
+    #[test]
+    fn test_session_refresh() {
+        let path = std::env::temp_dir().join(format!("smith-refresh-session-{}.json", std::process::id()));
+        let mut configuration = test_configuration();
+        configuration.credentials = Credentials::Session(path.clone());
+        let store = initialise(&configuration);
+        let session = store.login_with_browser(|url| {
+            let url = url.to_string();
+            thread::spawn(move || reqwest::get(&url).expect("Browser should succeed."));
+            Ok(())
+        }).expect("Browser login should succeed.");
+        let expired = Session { expires_at: 0, ..session.clone() };
+        expired.save(&path).expect("Session should save.");
+
+        let token = store.grant().expect("Refresh should succeed.");
+        assert_eq!(token, AccessToken { value: "mock".to_string() });
+        let refreshed = Session::load(&path).expect("Session should load.").expect("Session should exist.");
+        assert!(refreshed.expires_at > 0);
+        assert!(refreshed.refresh_token.is_some());
+        assert_ne!(refreshed.refresh_token, session.refresh_token);
+
+        // The original refresh token was rotated away, so can't be used again.
+        expired.save(&path).expect("Session should save.");
+        assert!(match store.renew(true) { Err(GrantError::LoginRequired) => true, _ => false });
+    }
+
+    #[test]
+    fn test_revoke() {
+        let path = std::env::temp_dir().join(format!("smith-revoke-session-{}.json", std::process::id()));
+        let mut configuration = test_configuration();
+        configuration.credentials = Credentials::Session(path.clone());
+        let store = initialise(&configuration);
+        let session = store.login_with_browser(|url| {
+            let url = url.to_string();
+            thread::spawn(move || reqwest::get(&url).expect("Browser should succeed."));
+            Ok(())
+        }).expect("Browser login should succeed.");
+        let refresh_token = session.refresh_token.clone().expect("Session should have a refresh token.");
+        store.revoke(&refresh_token, "refresh_token").expect("Revoke should succeed.");
+        store.revoke(&refresh_token, "refresh_token").expect("Revoking twice should succeed.");
+
+        Session { expires_at: 0, ..session }.save(&path).expect("Session should save.");
+        assert!(match store.renew(true) { Err(GrantError::LoginRequired) => true, _ => false });
+    }
+
+    #[test]
+    fn test_browser_login() {
+        let mut configuration = test_configuration();
+        configuration.credentials = Credentials::Session(std::env::temp_dir().join("smith-browser-session.json"));
+        let store = initialise(&configuration);
+        let mut browser = None;
+        let session = store.login_with_browser(|url| {
+            let url = url.to_string();
+            // Stands in for the browser, following the redirect back to the loopback listener.
+            browser = Some(thread::spawn(move || {
+                reqwest::get(&url).expect("Browser should succeed.").status()
+            }));
+            Ok(())
+        }).expect("Browser login should succeed.");
+        let status = browser.expect("Browser should be opened.").join().expect("Browser thread should succeed.");
+        assert_eq!(status, reqwest::StatusCode::OK);
+        assert_eq!(session.access_token, "mock".to_string());
+        assert!(session.refresh_token.is_some());
+    }
+
+    fn test_subject_token(expiry: i64) -> String {
+        let encode = |value: serde_json::Value| base64::encode_config(&value.to_string(), base64::URL_SAFE_NO_PAD);
+        let header = encode(serde_json::json!({ "alg": "RS256", "typ": "JWT" }));
+        let claims = encode(serde_json::json!({ "iss": "https://ci.example.com", "sub": "repo:smith", "exp": expiry }));
+        format!("{}.{}.signature", header, claims)
+    }
+
+    #[test]
+    fn test_token_exchange() {
+        let path = std::env::temp_dir().join(format!("smith-subject-token-{}", std::process::id()));
+        std::fs::write(&path, test_subject_token(4102444800)).expect("Subject token should be written.");
+        let mut configuration = test_configuration();
+        configuration.credentials = Credentials::TokenExchange(SubjectToken::File(path));
+        let token = initialise(&configuration).grant().expect("Grant should succeed.");
+        assert_eq!(token, AccessToken { value: "mock".to_string() });
+
+        let name = format!("SMITH_TEST_SUBJECT_TOKEN_{}", std::process::id());
+        std::env::set_var(&name, test_subject_token(4102444800));
+        configuration.credentials = Credentials::TokenExchange(SubjectToken::Env(name));
+        let token = initialise(&configuration).grant().expect("Grant should succeed.");
+        assert_eq!(token, AccessToken { value: "mock".to_string() });
+    }
+
+    #[test]
+    fn test_token_exchange_rejected() {
+        let name = format!("SMITH_TEST_EXPIRED_SUBJECT_TOKEN_{}", std::process::id());
+        std::env::set_var(&name, test_subject_token(0));
+        let mut configuration = test_configuration();
+        configuration.credentials = Credentials::TokenExchange(SubjectToken::Env(name));
+        let error = initialise(&configuration).grant();
+        assert!(match error { Err(GrantError::AccessTokenError(ref e)) if e.error == "invalid_grant" => true, _ => false });
+
+        configuration.credentials = Credentials::TokenExchange(SubjectToken::Env("SMITH_TEST_UNSET_SUBJECT_TOKEN".to_string()));
+        let error = initialise(&configuration).grant();
+        assert!(match error { Err(GrantError::SubjectTokenMissingError(_)) => true, _ => false });
+    }
+
+    #[test]
+    fn test_oauth_token_per_scopes() {
+        let store = initialise(&test_configuration());
+        let ca = vec!["ca".to_string()];
+        let both = vec!["profile".to_string(), "ca".to_string()];
+        store.grant_for(&ca).expect("Grant should succeed.");
+        assert!(store.local_for(&ca).is_some());
+        assert!(store.local_for(&both).is_none());
+        assert!(store.local().is_none());
+        store.grant_for(&both).expect("Grant should succeed.");
+        let mut reordered = both.clone();
+        reordered.reverse();
+        assert!(store.local_for(&reordered).is_some());
+        assert_ne!(store.cache_key_for(&ca).expect("Key should resolve."), store.cache_key_for(&both).expect("Key should resolve."));
+
+        let assertion = store.sign_for(&ca).expect("Sign should succeed.");
+        assert_eq!(decode_claims(&assertion)["scope"], "ca");
+    }
+
+    #[test]
+    fn test_oauth_token_shared() {
+        let store = Arc::new(initialise(&test_configuration()));
+        let threads = (0..8).map(|_| {
+            let store = store.clone();
+            thread::spawn(move || store.grant().expect("Grant should succeed."))
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            assert_eq!(thread.join().expect("Thread should succeed."), AccessToken { value: "mock".to_string() });
+        }
+    }
+
+    #[test]
+    fn test_oauth_token_background_refresh() {
+        let store = Arc::new(initialise(&test_configuration()));
+        let refresher = Refresher::spawn(&store, Duration::from_secs(60));
+        for _ in 0..50 {
+            if store.local().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        drop(refresher);
+        assert_eq!(store.local(), Some(AccessToken { value: "mock".to_string() }));
+    }
+
+    #[test]
+    fn test_until_refresh() {
+        let expires_at = SystemTime::now() + Duration::from_secs(3600);
+        assert!(until_refresh(expires_at, Duration::from_secs(600)) <= Duration::from_secs(3000));
+        assert!(until_refresh(expires_at, Duration::from_secs(600)) > Duration::from_secs(2990));
+        assert!(until_refresh(expires_at, Duration::from_secs(7200)) <= Duration::from_secs(1800));
+        assert_eq!(until_refresh(SystemTime::now(), Duration::from_secs(60)), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_oauth_token_cached() {
+        let directory = std::env::temp_dir().join(format!("smith-store-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let cache = TokenCache::new(directory.clone());
+        let mut configuration = test_configuration();
+        configuration.cache = Some(directory);
+
+        let store = initialise(&configuration);
+        let token = store.grant().expect("Grant should succeed.");
+        let cached = cache.load(&store.cache_key().expect("Key should resolve.")).expect("Load should succeed.");
+        assert_eq!(cached.map(|state| state.token), Some(token));
+
+        let state = AccessTokenState {
+            token: AccessToken { value: "cached".to_string() },
+            expires_at: SystemTime::now() + Duration::from_secs(3600),
+        };
+        cache.store(&store.cache_key().expect("Key should resolve."), &state).expect("Store should succeed.");
+        let other = initialise(&configuration);
+        assert_eq!(other.grant().expect("Grant should succeed."), state.token);
+    }
+
+    #[test]
+    fn test_assertion_claims() {
+        let store = initialise(&test_configuration());
+        let first = decode_claims(&store.sign().expect("Sign should succeed."));
+        let second = decode_claims(&store.sign().expect("Sign should succeed."));
+        let issued_at = first["iat"].as_i64().expect("iat should be set.");
+        assert_eq!(first["nbf"].as_i64(), Some(issued_at));
+        assert_eq!(first["exp"].as_i64(), Some(issued_at + 60));
+        assert!(first["jti"].is_string());
+        assert_ne!(first["jti"], second["jti"]);
+    }
+
+    #[test]
+    fn test_assertion_algorithms() {
+        let cases = vec![
+            ("test/data/credentials.json", "RS256"),
+            ("test/data/credentials-p256.json", "ES256"),
+            ("test/data/credentials-p384.json", "ES384"),
+            ("test/data/credentials-ed25519.json", "EdDSA"),
+        ];
+        for (credentials, algorithm) in cases {
+            let store = initialise(&test_configuration_with(Path::new(credentials)));
+            let assertion = store.sign().expect("Sign should succeed.");
+            let header = decode_part(&assertion, 0);
+            assert_eq!(header["alg"], algorithm);
+            assert_eq!(header["kid"], "mock");
+            store.exchange(&assertion).expect("Exchange should succeed.");
+        }
+    }
+
+    #[test]
+    fn test_dpop_token() {
+        let cases = vec![
+            ("test/data/credentials.json", "n"),
+            ("test/data/credentials-p256.json", "x"),
+            ("test/data/credentials-p384.json", "y"),
+            ("test/data/credentials-ed25519.json", "x"),
+        ];
+        for (credentials, member) in cases {
+            let mut configuration = test_configuration_with(Path::new(credentials));
+            configuration.dpop = Some(true);
+            let store = initialise(&configuration);
+            let proof = store.proof("GET", "http://localhost:8000/userinfo?q", Some("token"), None)
+                .expect("Proof should succeed.")
+                .expect("Proof should be enabled.");
+            let header = decode_part(&proof, 0);
+            let claims = decode_claims(&proof);
+            let jwk: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(credentials).expect("Credentials should exist."))
+                .expect("Credentials should be json.");
+            assert_eq!(header["typ"], "dpop+jwt");
+            assert_eq!(header["jwk"][member], jwk[member]);
+            assert!(header["jwk"].get("d").is_none());
+            assert_eq!(claims["htm"], "GET");
+            assert_eq!(claims["htu"], "http://localhost:8000/userinfo");
+
+            let key = match &store.configuration.credentials {
+                Credentials::Assertion(assertion) => assertion.key.clone(),
+                _ => panic!("Test credentials should be an assertion."),
+            };
+            let jkt = jws::thumbprint(&key.public_jwk().expect("Public key should succeed."));
+            let token = store.grant().expect("Grant should succeed.");
+            assert_eq!(token, AccessToken { value: format!("mock-dpop-{}", jkt) });
+        }
+    }
+
+    #[test]
+    fn test_dpop_nonce_new_assertion() {
+        // This endpoint uses up the assertion before asking for a nonce.
+        let mut configuration = test_configuration();
+        configuration.endpoint = format!("{}-strict", configuration.endpoint);
+        configuration.dpop = Some(true);
+        let store = initialise(&configuration);
+        store.refresh().expect("Retry with a nonce should sign a new assertion.");
+    }
+
+    fn with_discovery(issuer: &str, required: bool) -> Configuration {
+        let directory = std::env::temp_dir().join(format!("smith-discovery-{}-{}", std::process::id(), required));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut configuration = test_configuration();
+        configuration.dpop = None;
+        configuration.discovery = Some(Discovery {
+            issuer: issuer.to_string(),
+            cache: discovery::MetadataCache::new(directory, Duration::from_secs(3600)),
+            required,
+            keep_token_endpoint: false,
+        });
+        configuration
+    }
+
+    #[test]
+    fn test_endpoints_discovered() {
+        let store = initialise(&with_discovery(&server(), true));
+        let endpoints = store.endpoints().expect("Discovery should succeed.");
+        assert_eq!(endpoints.token, format!("{}/oauth/token", server()));
+        assert!(endpoints.dpop);
+        assert!(store.grant().expect("Grant should succeed.").value.starts_with("mock-dpop-"));
+    }
+
+    #[test]
+    fn test_endpoints_undiscovered() {
+        let unreachable = "http://localhost:1";
+        let store = initialise(&with_discovery(unreachable, false));
+        let endpoints = store.endpoints().expect("Configured endpoints should be used.");
+        assert_eq!(endpoints.token, store.configuration.endpoint);
+        assert!(!endpoints.dpop);
+        let store = initialise(&with_discovery(unreachable, true));
+        assert!(match store.endpoints() { Err(GrantError::DiscoveryError(_)) => true, _ => false });
+    }
+
+    #[test]
+    fn test_inconsistent_algorithm() {
+        let mut jwk: serde_json::Value = serde_json::from_str(include_str!("../test/data/credentials-p256.json"))
+            .expect("Should be able to parse credentials.");
+        jwk["alg"] = serde_json::Value::String("ES384".to_string());
+        let jwk: Jwk<IdentityId> = serde_json::from_value(jwk).expect("Should be able to deserialise credentials.");
+        match Configuration::build_secret(&jwk) {
+            Err(KeyError::InconsistentKeyError) => (),
+            r => panic!("Mismatched alg and crv should be rejected: {:?}", r.map(|key| key.algorithm())),
+        }
+    }
+
+    #[test]
+    fn test_oauth_token_expired_assertion() {
+        let mut configuration = test_configuration();
+        configuration.assertion_lifetime = Duration::from_secs(0);
+        let store = initialise(&configuration);
+        match store.refresh() {
+            Err(GrantError::AccessTokenError(e)) => assert_eq!(e.error, "invalid_grant"),
+            r => panic!("Expired assertion should be rejected: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_oauth_token_replayed_assertion() {
+        let store = initialise(&test_configuration());
+        let assertion = store.sign().expect("Sign should succeed.");
+        store.exchange(&assertion).expect("First exchange should succeed.");
+        match store.exchange(&assertion) {
+            Err(GrantError::AccessTokenError(e)) => assert_eq!(e.error, "invalid_grant"),
+            r => panic!("Replayed assertion should be rejected: {:?}", r),
+        }
+    }
 }
@@ -0,0 +1,225 @@
+use crate::oauth2::{self, cache, AccessToken, AccessTokenError, AccessTokenResponse, AccessTokenState, Configuration, Credentials, Endpoints, GrantError, SubjectToken};
+use crate::retry;
+use crate::transport::{self, Request};
+
+use futures::future::{self, Either, Future, Shared};
+use futures::sync::oneshot;
+use reqwest::r#async::Client;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// A future that can be spawned on a tokio runtime.
+pub type Response<T, E> = Box<dyn Future<Item = T, Error = E> + Send>;
+
+/// Refreshes in progress by scope set, each finishes when its
+/// refresh does.
+type Refreshing = Arc<Mutex<HashMap<Vec<String>, Shared<Response<(), ()>>>>>;
+
+/// A token store for `AsyncApi`, tokens are held in memory per set of
+/// scopes. Only credentials that don't need local state (a JWK or a
+/// subject token) are supported, and tokens are always bearer tokens.
+/// Grants aren't retried here, `AsyncApi` retries the whole request.
+#[derive(Clone)]
+pub struct AsyncStore {
+    pub client: Client,
+    /// Discovery is made with this, on a thread of its own.
+    pub blocking_client: reqwest::Client,
+    pub configuration: Configuration,
+    state: Arc<Mutex<HashMap<Vec<String>, AccessTokenState>>>,
+    refreshing: Refreshing,
+    endpoints: Arc<Mutex<Option<Endpoints>>>,
+}
+
+impl AsyncStore {
+    pub fn new(client: Client, blocking_client: reqwest::Client, configuration: Configuration) -> AsyncStore {
+        AsyncStore {
+            client,
+            blocking_client,
+            configuration,
+            state: Arc::new(Mutex::new(HashMap::new())),
+            refreshing: Arc::new(Mutex::new(HashMap::new())),
+            endpoints: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The endpoints, discovered on first use.
+    pub fn endpoints(&self) -> Response<Endpoints, GrantError> {
+        if let Some(ref endpoints) = *oauth2::lock(&self.endpoints) {
+            return Box::new(future::ok(endpoints.clone()));
+        }
+        let (configuration, client, resolved) = (self.configuration.clone(), self.blocking_client.clone(), self.endpoints.clone());
+        blocking(move || {
+            let endpoints = configuration.endpoints(&client)?;
+            *oauth2::lock(&resolved) = Some(endpoints.clone());
+            Ok(endpoints)
+        })
+    }
+
+    /// A token for just `scopes`.
+    pub fn grant_for(&self, scopes: &[String]) -> Response<AccessToken, GrantError> {
+        match self.local_for(scopes) {
+            Some(token) => Box::new(future::ok(token)),
+            None => self.renew_for(scopes),
+        }
+    }
+
+    pub fn local_for(&self, scopes: &[String]) -> Option<AccessToken> {
+        oauth2::lock(&self.state).get(&oauth2::scope_set(scopes)).and_then(|state| {
+            if cache::is_fresh(state, self.configuration.refresh_margin) {
+                Some(state.token.clone())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Obtain a new token, callers for the same scopes while one is
+    /// being obtained wait for it, as with `Store`. Should it fail,
+    /// each of them then tries again.
+    pub fn renew_for(&self, scopes: &[String]) -> Response<AccessToken, GrantError> {
+        let key = oauth2::scope_set(scopes);
+        let mut refreshing = oauth2::lock(&self.refreshing);
+        if let Some(done) = refreshing.get(&key).cloned() {
+            let (store, scopes) = (self.clone(), scopes.to_vec());
+            return Box::new(done.then(move |_done| match store.local_for(&scopes) {
+                Some(token) => Either::A(future::ok(token)),
+                None => Either::B(store.renew_for(&scopes)),
+            }));
+        }
+        let (sender, receiver) = oneshot::channel();
+        let done: Response<(), ()> = Box::new(receiver.map_err(|_canceled| ()));
+        refreshing.insert(key.clone(), done.shared());
+        drop(refreshing);
+        let pending = Pending { refreshing: self.refreshing.clone(), key, _done: sender };
+        Box::new(self.refresh_for(scopes).then(move |result| {
+            drop(pending);
+            result
+        }))
+    }
+
+    /// Obtain a new token for `scopes` with a single request.
+    fn refresh_for(&self, scopes: &[String]) -> Response<AccessToken, GrantError> {
+        let store = self.clone();
+        let key = oauth2::scope_set(scopes);
+        let state = self.state.clone();
+        let requested_at = SystemTime::now();
+        let response = self.parameters(scopes)
+            .join(self.endpoints())
+            .and_then(|(parameters, endpoints)| oauth2::url(&endpoints.token).map(|url| (parameters, url)))
+            .and_then(move |(parameters, url)| store.send(Request::form(url, &parameters)))
+            .and_then(|response| match response.status {
+                reqwest::StatusCode::OK => serde_json::from_slice::<AccessTokenResponse>(&response.body)
+                    .map_err(|e| GrantError::Json200ParseError(e)),
+                reqwest::StatusCode::BAD_REQUEST => match serde_json::from_slice::<AccessTokenError>(&response.body) {
+                    Ok(error) => Err(GrantError::AccessTokenError(error)),
+                    Err(e) => Err(GrantError::Json400ParseError(e)),
+                },
+                s if retry::is_retryable(s) => Err(GrantError::UnavailableError(s, retry::retry_after(&response.headers))),
+                s => Err(GrantError::InvalidStatusCodeError(s)),
+            })
+            .map(move |response| {
+                let token = AccessToken { value: response.access_token };
+                oauth2::lock(&state).insert(key, AccessTokenState {
+                    token: token.clone(),
+                    expires_at: requested_at + Duration::from_secs(response.expires_in),
+                });
+                token
+            });
+        Box::new(response)
+    }
+
+    /// Send `request`, through the pins if configured.
+    pub(crate) fn send(&self, request: Request) -> Response<transport::Response, GrantError> {
+        match self.configuration.pins {
+            Some(ref pins) => Box::new(pins.send_async(request).map_err(|e| GrantError::PinningError(e))),
+            None => Box::new(transport::send_async_with(&self.client, request).map_err(|e| GrantError::NetworkError(e))),
+        }
+    }
+
+    /// The grant parameters, a subject token in a file is read on a
+    /// thread of its own.
+    fn parameters(&self, scopes: &[String]) -> Response<Vec<(&'static str, String)>, GrantError> {
+        let (configuration, scopes) = (self.configuration.clone(), scopes.to_vec());
+        match &self.configuration.credentials {
+            Credentials::Assertion(_) => Box::new(future::result(configuration.sign_for(&scopes).map(|assertion| vec![
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer".to_string()),
+                ("assertion", assertion),
+            ]))),
+            Credentials::TokenExchange(subject @ SubjectToken::File(_)) => {
+                let subject = subject.clone();
+                blocking(move || Ok(configuration.token_exchange_parameters(&subject.read()?, &scopes)))
+            },
+            Credentials::TokenExchange(subject) => Box::new(future::result(subject.read().map(|subject_token| {
+                configuration.token_exchange_parameters(&subject_token, &scopes)
+            }))),
+            Credentials::Session(_) => Box::new(future::err(GrantError::UnsupportedCredentialsError)),
+        }
+    }
+}
+
+/// A refresh in progress, it is no longer pending once finished or
+/// dropped, which wakes those waiting for it.
+struct Pending {
+    refreshing: Refreshing,
+    key: Vec<String>,
+    _done: oneshot::Sender<()>,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        oauth2::lock(&self.refreshing).remove(&self.key);
+    }
+}
+
+/// Run `f` on a thread of its own, for file and blocking network
+/// access that would otherwise hold up the executor.
+fn blocking<T, F>(f: F) -> Response<T, GrantError>
+    where F: FnOnce() -> Result<T, GrantError> + Send + 'static, T: Send + 'static {
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = sender.send(f());
+    });
+    Box::new(receiver.then(|result| result.unwrap_or(Err(GrantError::CanceledError))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::runtime::Runtime;
+
+    fn test_store(configuration: Configuration) -> AsyncStore {
+        let settings = crate::http::Settings::default();
+        let client = settings.async_client().expect("Client should build.");
+        AsyncStore::new(client, settings.client().expect("Client should build."), configuration)
+    }
+
+    fn server() -> String {
+        std::env::var("SERVER").unwrap_or("http://localhost:8000".to_string())
+    }
+
+    #[test]
+    fn test_renew_shared() {
+        let store = test_store(Configuration::for_tests(&server()));
+        let scopes = vec!["profile".to_string()];
+        let first = store.renew_for(&scopes);
+        let second = store.renew_for(&scopes);
+        assert_eq!(oauth2::lock(&store.refreshing).len(), 1);
+        let (first, second) = Runtime::new().expect("Runtime should start.").block_on(first.join(second)).expect("Renew should succeed.");
+        assert_eq!(first, second);
+        assert!(oauth2::lock(&store.refreshing).is_empty());
+        drop(store.renew_for(&scopes));
+        assert!(oauth2::lock(&store.refreshing).is_empty());
+    }
+
+    #[test]
+    fn test_subject_token_file_missing() {
+        let mut configuration = Configuration::for_tests(&server());
+        let path = std::env::temp_dir().join(format!("smith-missing-subject-token-{}", std::process::id()));
+        configuration.credentials = Credentials::TokenExchange(SubjectToken::File(path));
+        let result = Runtime::new().expect("Runtime should start.").block_on(test_store(configuration).grant_for(&["profile".to_string()]));
+        assert!(match result { Err(GrantError::SubjectTokenError(_)) => true, _ => false });
+    }
+}
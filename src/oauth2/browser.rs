@@ -0,0 +1,152 @@
+use crate::oauth2::{self, AccessTokenError, GrantError, Store};
+use crate::oauth2::session::Session;
+
+use reqwest::Url;
+use ring::digest;
+use std::io::prelude::*;
+use std::io::{BufReader, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// https://tools.ietf.org/html/rfc6749#section-4.1
+// https://tools.ietf.org/html/rfc7636
+// https://tools.ietf.org/html/rfc8252#section-7.3
+
+/// How long to wait for the browser to redirect back.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Pkce {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl Pkce {
+    pub fn generate() -> Result<Pkce, GrantError> {
+        let verifier = oauth2::random(32)?;
+        let challenge = Pkce::challenge(&verifier);
+        Ok(Pkce { verifier, challenge })
+    }
+
+    pub fn challenge(verifier: &str) -> String {
+        let hash = digest::digest(&digest::SHA256, verifier.as_bytes());
+        base64::encode_config(hash.as_ref(), base64::URL_SAFE_NO_PAD)
+    }
+}
+
+impl Store {
+    /// Log in with the authorization code grant, `open` is given the
+    /// authorization url to show the user, the authorization server
+    /// redirects their browser back to a listener on the loopback
+    /// interface.
+    pub fn login_with_browser<F>(&self, open: F) -> Result<Session, GrantError>
+        where F: FnOnce(&str) -> std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|e| GrantError::LoopbackError(e))?;
+        let port = listener.local_addr().map_err(|e| GrantError::LoopbackError(e))?.port();
+        let redirect_uri = format!("http://127.0.0.1:{}/callback", port);
+        let state = oauth2::random(16)?;
+        let pkce = Pkce::generate()?;
+        let endpoint = self.endpoints()?.authorization;
+        let url = Url::parse_with_params(&endpoint, &[
+            ("response_type", "code"),
+            ("client_id", &self.configuration.client_id),
+            ("redirect_uri", &redirect_uri),
+            ("scope", &self.configuration.scopes.join(" ")),
+            ("state", &state),
+            ("code_challenge", &pkce.challenge),
+            ("code_challenge_method", "S256"),
+        ]).map_err(|_e| GrantError::InvalidEndpointError(endpoint.clone()))?;
+        open(url.as_str()).map_err(|e| GrantError::LoopbackError(e))?;
+
+        let code = receive(&listener, &state)?;
+        let requested_at = SystemTime::now();
+        let response = self.token(&[
+            ("grant_type", "authorization_code".to_string()),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", self.configuration.client_id.clone()),
+            ("code_verifier", pkce.verifier),
+        ])?;
+        Ok(Session::from_response(&response, requested_at))
+    }
+}
+
+/// Wait for the redirect carrying the authorization code, requests
+/// for anything else (e.g. a favicon) are turned away.
+fn receive(listener: &TcpListener, state: &str) -> Result<String, GrantError> {
+    let deadline = Instant::now() + LOGIN_TIMEOUT;
+    listener.set_nonblocking(true).map_err(|e| GrantError::LoopbackError(e))?;
+    loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                if Instant::now() > deadline {
+                    return Err(GrantError::LoginTimeoutError);
+                }
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            },
+            Err(e) => return Err(GrantError::LoopbackError(e)),
+        };
+        if let Some(result) = callback(stream, state)? {
+            return result;
+        }
+    }
+}
+
+fn callback(mut stream: TcpStream, state: &str) -> Result<Option<Result<String, GrantError>>, GrantError> {
+    stream.set_nonblocking(false).map_err(|e| GrantError::LoopbackError(e))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10))).map_err(|e| GrantError::LoopbackError(e))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line).map_err(|e| GrantError::LoopbackError(e))?;
+    // Request line, e.g. "GET /callback?code=...&state=... HTTP/1.1".
+    let target = line.split(' ').nth(1).unwrap_or("");
+    let url = match Url::parse(&format!("http://127.0.0.1{}", target)) {
+        Ok(ref url) if url.path() == "/callback" => url.clone(),
+        _ => {
+            respond(&mut stream, "404 Not Found", "Not found.")?;
+            return Ok(None);
+        },
+    };
+    let parameter = |name: &str| url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.to_string());
+    let result = if parameter("state") != Some(state.to_string()) {
+        Err(GrantError::StateMismatchError)
+    } else if let Some(error) = parameter("error") {
+        Err(GrantError::AccessTokenError(AccessTokenError { error }))
+    } else {
+        parameter("code").ok_or(GrantError::MissingCodeError)
+    };
+    match result {
+        Ok(_) => respond(&mut stream, "200 OK", "Logged in to smith, you can close this window.")?,
+        Err(_) => respond(&mut stream, "400 Bad Request", "Login to smith failed, please return to your terminal.")?,
+    }
+    Ok(Some(result))
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), GrantError> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).map_err(|e| GrantError::LoopbackError(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkce_challenge() {
+        // https://tools.ietf.org/html/rfc7636#appendix-B
+        let challenge = Pkce::challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk");
+        assert_eq!(challenge, "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+    }
+
+    #[test]
+    fn test_pkce_generate() {
+        let pkce = Pkce::generate().expect("Generate should succeed.");
+        assert!(pkce.verifier.len() >= 43);
+        assert_eq!(pkce.challenge, Pkce::challenge(&pkce.verifier));
+    }
+}
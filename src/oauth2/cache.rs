@@ -0,0 +1,272 @@
+use crate::file;
+use crate::oauth2::{AccessToken, AccessTokenState};
+
+use fs2::FileExt;
+use ring::digest;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// On-disk access tokens, shared between smith processes so that
+/// repeated invocations don't each need a new grant.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TokenCache {
+    pub directory: PathBuf,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CacheKey {
+    pub endpoint: String,
+    pub identity: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum CacheError {
+    IoError(std::io::Error),
+    JsonError(serde_json::Error),
+}
+
+impl From<std::io::Error> for CacheError {
+    fn from(err: std::io::Error) -> CacheError {
+        CacheError::IoError(err)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct CachedToken {
+    access_token: String,
+    expires_at: u64,
+}
+
+impl CachedToken {
+    fn state(self) -> AccessTokenState {
+        AccessTokenState {
+            token: AccessToken { value: self.access_token },
+            expires_at: UNIX_EPOCH + Duration::from_secs(self.expires_at),
+        }
+    }
+}
+
+/// An exclusive lock on a cache entry, released on drop.
+pub struct CacheLock {
+    file: File,
+}
+
+impl CacheLock {
+    /// Block until no other process holds the lock file at `path`.
+    pub fn acquire(path: &Path) -> std::io::Result<CacheLock> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .mode(0o600)
+            .open(path)?;
+        file.lock_exclusive()?;
+        Ok(CacheLock { file })
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+impl CacheKey {
+    fn digest(&self) -> String {
+        let mut scopes = self.scopes.clone();
+        scopes.sort();
+        scopes.dedup();
+        let key = format!("{}\n{}\n{}", self.endpoint, self.identity, scopes.join(" "));
+        hex_digest(&key)
+    }
+}
+
+/// Hex encoded SHA-256 of `value`, for use in file names.
+pub fn hex_digest(value: &str) -> String {
+    digest::digest(&digest::SHA256, value.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl TokenCache {
+    pub fn new(directory: PathBuf) -> TokenCache {
+        TokenCache { directory }
+    }
+
+    pub fn path(&self, key: &CacheKey) -> PathBuf {
+        self.directory.join(format!("token-{}.json", key.digest()))
+    }
+
+    fn create_directory(&self) -> Result<(), CacheError> {
+        DirBuilder::new().recursive(true).mode(0o700).create(&self.directory)?;
+        Ok(())
+    }
+
+    /// Block until no other process holds the lock for `key`, callers
+    /// should hold this across load, refresh and store.
+    pub fn lock(&self, key: &CacheKey) -> Result<CacheLock, CacheError> {
+        self.create_directory()?;
+        let lock = CacheLock::acquire(&self.directory.join(format!("token-{}.lock", key.digest())))?;
+        Ok(lock)
+    }
+
+    pub fn load(&self, key: &CacheKey) -> Result<Option<AccessTokenState>, CacheError> {
+        let mut file = match File::open(self.path(key)) {
+            Ok(file) => file,
+            Err(err) =>
+                if err.kind() == ErrorKind::NotFound {
+                    return Ok(None);
+                } else {
+                    return Err(CacheError::IoError(err));
+                }
+        };
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let cached: CachedToken = serde_json::from_str(&contents).map_err(|e| CacheError::JsonError(e))?;
+        Ok(Some(cached.state()))
+    }
+
+    pub fn store(&self, key: &CacheKey, state: &AccessTokenState) -> Result<(), CacheError> {
+        self.create_directory()?;
+        let expires_at = state.expires_at.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let cached = CachedToken {
+            access_token: state.token.value.clone(),
+            expires_at: expires_at.as_secs(),
+        };
+        let contents = serde_json::to_vec(&cached).map_err(|e| CacheError::JsonError(e))?;
+        file::write_atomic(&self.path(key), &contents, &file::Options::private())?;
+        Ok(())
+    }
+
+    /// Every cached token, unreadable entries are skipped.
+    pub fn tokens(&self) -> Result<Vec<AccessTokenState>, CacheError> {
+        let mut tokens = vec![];
+        for path in self.files(".json")? {
+            if let Ok(contents) = std::fs::read_to_string(&path) {
+                if let Ok(cached) = serde_json::from_str::<CachedToken>(&contents) {
+                    tokens.push(cached.state());
+                }
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Remove every cached token, anything else in the directory, like
+    /// discovery metadata, is left alone. Lock files are kept, another
+    /// process may hold one, and removing it would let a third lock a
+    /// new file at the same path while the first is still writing.
+    pub fn clear(&self) -> Result<(), CacheError> {
+        for path in self.files(".json")? {
+            match std::fs::remove_file(&path) {
+                Ok(()) => (),
+                Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+                Err(err) => return Err(CacheError::IoError(err)),
+            }
+        }
+        Ok(())
+    }
+
+    /// The cache's own files ending in `suffix`.
+    fn files(&self, suffix: &str) -> Result<Vec<PathBuf>, CacheError> {
+        let entries = match std::fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(CacheError::IoError(err)),
+        };
+        let mut files = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            if name.starts_with("token-") && name.ends_with(suffix) {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    pub fn remove(&self, key: &CacheKey) -> Result<(), CacheError> {
+        match std::fs::remove_file(self.path(key)) {
+            Ok(()) => Ok(()),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(CacheError::IoError(err)),
+        }
+    }
+}
+
+/// Is the token still usable for at least `margin`.
+pub fn is_fresh(state: &AccessTokenState, margin: Duration) -> bool {
+    SystemTime::now()
+        .checked_add(margin)
+        .map(|deadline| deadline < state.expires_at)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn test_cache(name: &str) -> TokenCache {
+        let directory = std::env::temp_dir().join(format!("smith-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        TokenCache::new(directory)
+    }
+
+    fn test_key() -> CacheKey {
+        CacheKey {
+            endpoint: "http://localhost:8000/oauth/token".to_string(),
+            identity: "me".to_string(),
+            scopes: vec!["profile".to_string(), "ca".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_store_and_load() {
+        let cache = test_cache("roundtrip");
+        let state = AccessTokenState {
+            token: AccessToken { value: "cached".to_string() },
+            expires_at: UNIX_EPOCH + Duration::from_secs(4102444800),
+        };
+        assert_eq!(cache.load(&test_key()).expect("Load should succeed."), None);
+        cache.store(&test_key(), &state).expect("Store should succeed.");
+        assert_eq!(cache.load(&test_key()).expect("Load should succeed."), Some(state.clone()));
+        assert_eq!(cache.tokens().expect("Tokens should succeed."), vec![state]);
+        let mode = std::fs::metadata(cache.path(&test_key())).expect("Cache file should exist.").permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let _lock = cache.lock(&test_key()).expect("Lock should succeed.");
+        let other = cache.directory.join("metadata-other.json");
+        std::fs::write(&other, "{}").expect("Other file should be written.");
+        cache.clear().expect("Clear should succeed.");
+        assert_eq!(cache.tokens().expect("Tokens should succeed."), vec![]);
+        assert_eq!(std::fs::read_dir(&cache.directory).expect("Directory should remain.").count(), 2);
+        assert!(other.exists());
+        assert!(cache.directory.join(format!("token-{}.lock", test_key().digest())).exists());
+        std::fs::remove_dir_all(&cache.directory).expect("Directory should be removed.");
+    }
+
+    #[test]
+    fn test_key_ignores_scope_order() {
+        let mut reordered = test_key();
+        reordered.scopes.reverse();
+        assert_eq!(test_key().digest(), reordered.digest());
+        let mut other = test_key();
+        other.identity = "you".to_string();
+        assert_ne!(test_key().digest(), other.digest());
+    }
+
+    #[test]
+    fn test_is_fresh() {
+        let state = AccessTokenState {
+            token: AccessToken { value: "cached".to_string() },
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+        };
+        assert!(is_fresh(&state, Duration::from_secs(0)));
+        assert!(!is_fresh(&state, Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,107 @@
+use crate::oauth2::{self, AccessTokenError, GrantError, Store};
+use crate::oauth2::session::Session;
+use crate::transport::Request;
+
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// https://tools.ietf.org/html/rfc8628
+
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Default polling interval, and the increment on `slow_down`.
+const INTERVAL: u64 = 5;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DeviceAuthorization {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    #[serde(default)]
+    pub verification_uri_complete: Option<String>,
+    pub expires_in: u64,
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+fn default_interval() -> u64 {
+    INTERVAL
+}
+
+impl Store {
+    /// Start a device authorization, the user should be shown the
+    /// verification uri and user code before polling.
+    pub fn authorize_device(&self) -> Result<DeviceAuthorization, GrantError> {
+        let parameters = vec![
+            ("client_id", self.configuration.client_id.clone()),
+            ("scope", self.configuration.scopes.join(" ")),
+        ];
+        let response = self.send(Request::form(oauth2::url(&self.endpoints()?.device)?, &parameters))?;
+
+        match response.status {
+            reqwest::StatusCode::OK => {
+                serde_json::from_slice(&response.body)
+                    .map_err(|e| GrantError::Json200ParseError(e))
+            },
+            reqwest::StatusCode::BAD_REQUEST => {
+                let error: AccessTokenError = serde_json::from_slice(&response.body)
+                    .map_err(|e| GrantError::Json400ParseError(e))?;
+                Err(GrantError::AccessTokenError(error))
+            },
+            s => {
+                Err(GrantError::InvalidStatusCodeError(s))
+            },
+        }
+    }
+
+    /// Poll until the user approves or denies the authorization, or it
+    /// expires.
+    pub fn poll_device(&self, authorization: &DeviceAuthorization) -> Result<Session, GrantError> {
+        let deadline = Instant::now() + Duration::from_secs(authorization.expires_in);
+        let mut interval = Duration::from_secs(authorization.interval);
+        let parameters = vec![
+            ("grant_type", DEVICE_CODE_GRANT.to_string()),
+            ("device_code", authorization.device_code.clone()),
+            ("client_id", self.configuration.client_id.clone()),
+        ];
+        loop {
+            thread::sleep(interval);
+            if Instant::now() > deadline {
+                return Err(GrantError::DeviceAuthorizationExpiredError);
+            }
+            let requested_at = SystemTime::now();
+            match self.token(&parameters) {
+                Ok(response) => return Ok(Session::from_response(&response, requested_at)),
+                Err(GrantError::AccessTokenError(error)) => match next_interval(interval, &error.error) {
+                    Some(next) => interval = next,
+                    None => return Err(GrantError::AccessTokenError(error)),
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// The interval to continue polling with, or None if `error` means
+/// polling should stop.
+fn next_interval(interval: Duration, error: &str) -> Option<Duration> {
+    match error {
+        "authorization_pending" => Some(interval),
+        "slow_down" => Some(interval + Duration::from_secs(INTERVAL)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_interval() {
+        let interval = Duration::from_secs(1);
+        assert_eq!(next_interval(interval, "authorization_pending"), Some(interval));
+        assert_eq!(next_interval(interval, "slow_down"), Some(Duration::from_secs(6)));
+        assert_eq!(next_interval(interval, "access_denied"), None);
+        assert_eq!(next_interval(interval, "expired_token"), None);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::file;
+use crate::oauth2::cache;
+use crate::pinning::{PinError, Pinning};
+use crate::transport::{self, Request, Response};
+
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT};
+use reqwest::{Method, Url};
+use std::fmt;
+use std::fs::DirBuilder;
+use std::io::ErrorKind;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// https://tools.ietf.org/html/rfc8414
+// https://openid.net/specs/openid-connect-discovery-1_0.html
+
+const WELL_KNOWN: &[&str] = &[
+    ".well-known/oauth-authorization-server",
+    ".well-known/openid-configuration",
+];
+
+/// Authorization server metadata, only the members smith uses.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    pub issuer: String,
+    pub token_endpoint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authorization_endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_authorization_endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revocation_endpoint: Option<String>,
+    #[serde(default)]
+    pub token_endpoint_auth_signing_alg_values_supported: Vec<String>,
+    #[serde(default)]
+    pub dpop_signing_alg_values_supported: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    InvalidUrlError(String),
+    NetworkError(reqwest::Error),
+    PinningError(PinError),
+    InvalidStatusCodeError(reqwest::StatusCode),
+    JsonParseError(serde_json::Error),
+    IssuerMismatchError(String, String),
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::InvalidUrlError(url) =>
+              write!(f, "Authorization server metadata url '{}' is not valid, check SMITH_ENDPOINT.", url),
+            DiscoveryError::PinningError(e) =>
+              write!(f, "{}", e),
+            DiscoveryError::NetworkError(_) =>
+              write!(f, "Authorization server metadata could not be fetched, check the endpoint is reachable."),
+            DiscoveryError::InvalidStatusCodeError(status) =>
+              write!(f, "Authorization server metadata could not be fetched, the server responded {}.", status),
+            DiscoveryError::JsonParseError(_) =>
+              write!(f, "Authorization server metadata is not valid JSON."),
+            DiscoveryError::IssuerMismatchError(endpoint, issuer) =>
+              write!(f, "Authorization server metadata for {} is for another issuer, {}, refusing to use it.", endpoint, issuer),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct CachedMetadata {
+    fetched_at: u64,
+    metadata: Metadata,
+}
+
+/// Where a store discovers its endpoints from, on first use.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Discovery {
+    pub issuer: String,
+    pub cache: MetadataCache,
+    /// Fail without metadata, rather than use the configured endpoints.
+    pub required: bool,
+    /// The token endpoint was configured, so the discovered one is
+    /// ignored.
+    pub keep_token_endpoint: bool,
+}
+
+/// Metadata cached on disk in `directory` for `ttl`, as it rarely
+/// changes and is needed on every invocation.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MetadataCache {
+    pub directory: PathBuf,
+    pub ttl: Duration,
+}
+
+impl Metadata {
+    /// Fetch the metadata for the server at `endpoint`, trying the
+    /// OAuth location before the OpenID one. Metadata for any issuer
+    /// other than `endpoint` is rejected, as RFC 8414 section 3.3
+    /// requires. Requests go through `pins` if set.
+    pub fn discover(client: &reqwest::Client, pins: &Option<Arc<Pinning>>, endpoint: &str) -> Result<Metadata, DiscoveryError> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let mut result = Err(DiscoveryError::InvalidStatusCodeError(reqwest::StatusCode::NOT_FOUND));
+        for path in WELL_KNOWN {
+            result = Metadata::fetch(client, pins, &format!("{}/{}", endpoint, path));
+            match result {
+                Err(DiscoveryError::InvalidStatusCodeError(reqwest::StatusCode::NOT_FOUND)) => continue,
+                _ => break,
+            }
+        }
+        let metadata = result?;
+        if metadata.issuer.trim_end_matches('/') != endpoint {
+            return Err(DiscoveryError::IssuerMismatchError(endpoint.to_string(), metadata.issuer));
+        }
+        Ok(metadata)
+    }
+
+    fn fetch(client: &reqwest::Client, pins: &Option<Arc<Pinning>>, url: &str) -> Result<Metadata, DiscoveryError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        let request = Request {
+            method: Method::GET,
+            url: Url::parse(url).map_err(|_e| DiscoveryError::InvalidUrlError(url.to_string()))?,
+            headers,
+            body: None,
+        };
+        let response: Response = match pins {
+            Some(ref pins) => pins.send(request).map_err(|e| DiscoveryError::PinningError(e))?,
+            None => transport::send_with(client, request).map_err(|e| DiscoveryError::NetworkError(e))?,
+        };
+        match response.status {
+            reqwest::StatusCode::OK => {
+                serde_json::from_slice(&response.body)
+                    .map_err(|e| DiscoveryError::JsonParseError(e))
+            },
+            s => {
+                Err(DiscoveryError::InvalidStatusCodeError(s))
+            },
+        }
+    }
+
+    /// Is `algorithm` acceptable for signing assertions, servers that
+    /// don't say are assumed to accept anything.
+    pub fn supports_assertion(&self, algorithm: &str) -> bool {
+        self.token_endpoint_auth_signing_alg_values_supported.is_empty()
+            || self.token_endpoint_auth_signing_alg_values_supported.iter().any(|a| a == algorithm)
+    }
+
+    pub fn supports_dpop(&self, algorithm: &str) -> bool {
+        self.dpop_signing_alg_values_supported.iter().any(|a| a == algorithm)
+    }
+}
+
+impl MetadataCache {
+    pub fn new(directory: PathBuf, ttl: Duration) -> MetadataCache {
+        MetadataCache { directory, ttl }
+    }
+
+    pub fn path(&self, endpoint: &str) -> PathBuf {
+        self.directory.join(format!("metadata-{}.json", cache::hex_digest(endpoint)))
+    }
+
+    /// Cached metadata for `endpoint` if fresh, otherwise discovered.
+    /// The cache is best effort, failures to read or write it only
+    /// mean another fetch.
+    pub fn discover(&self, client: &reqwest::Client, pins: &Option<Arc<Pinning>>, endpoint: &str) -> Result<Metadata, DiscoveryError> {
+        let path = self.path(endpoint);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs();
+        if let Some(cached) = load(&path) {
+            if cached.fetched_at <= now && now < cached.fetched_at + self.ttl.as_secs() {
+                return Ok(cached.metadata);
+            }
+        }
+        let metadata = Metadata::discover(client, pins, endpoint)?;
+        let _ = self.store(&path, &CachedMetadata { fetched_at: now, metadata: metadata.clone() });
+        Ok(metadata)
+    }
+
+    fn store(&self, path: &Path, cached: &CachedMetadata) -> std::io::Result<()> {
+        DirBuilder::new().recursive(true).mode(0o700).create(&self.directory)?;
+        let contents = serde_json::to_vec(cached).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        file::write_atomic(path, &contents, &file::Options::private())
+    }
+}
+
+fn load(path: &Path) -> Option<CachedMetadata> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> String {
+        std::env::var("SERVER").unwrap_or("http://localhost:8000".to_string())
+    }
+
+    #[test]
+    fn test_discover() {
+        let metadata = Metadata::discover(&reqwest::Client::new(), &None, &server()).expect("Discovery should succeed.");
+        assert_eq!(metadata.token_endpoint, format!("{}/oauth/token", server()));
+        assert!(metadata.revocation_endpoint.is_some());
+        assert!(metadata.supports_assertion("ES256"));
+        assert!(!metadata.supports_assertion("HS256"));
+    }
+
+    #[test]
+    fn test_discover_openid() {
+        let endpoint = format!("{}/oidc", server());
+        let metadata = Metadata::discover(&reqwest::Client::new(), &None, &endpoint).expect("Discovery should succeed.");
+        assert_eq!(metadata.token_endpoint, format!("{}/oauth/token", server()));
+        assert_eq!(metadata.revocation_endpoint, None);
+    }
+
+    #[test]
+    fn test_discover_issuer_mismatch() {
+        let endpoint = format!("{}/mismatched", server());
+        match Metadata::discover(&reqwest::Client::new(), &None, &endpoint) {
+            Err(DiscoveryError::IssuerMismatchError(_, issuer)) => assert_eq!(issuer, server()),
+            r => panic!("Metadata for another issuer should be rejected: {:?}", r),
+        }
+        Metadata::discover(&reqwest::Client::new(), &None, &format!("{}/", server())).expect("Trailing slash should match.");
+    }
+
+    #[test]
+    fn test_discover_cached() {
+        let directory = std::env::temp_dir().join(format!("smith-metadata-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let cache = MetadataCache::new(directory, Duration::from_secs(3600));
+        let endpoint = "http://localhost:1/unreachable";
+        let metadata = Metadata {
+            issuer: endpoint.to_string(),
+            token_endpoint: format!("{}/token", endpoint),
+            authorization_endpoint: None,
+            device_authorization_endpoint: None,
+            revocation_endpoint: None,
+            token_endpoint_auth_signing_alg_values_supported: vec![],
+            dpop_signing_alg_values_supported: vec![],
+        };
+        assert!(cache.discover(&reqwest::Client::new(), &None, endpoint).is_err());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Clock should be valid.").as_secs();
+        cache.store(&cache.path(endpoint), &CachedMetadata { fetched_at: now, metadata: metadata.clone() })
+            .expect("Store should succeed.");
+        assert_eq!(cache.discover(&reqwest::Client::new(), &None, endpoint).expect("Cache should be used."), metadata);
+        cache.store(&cache.path(endpoint), &CachedMetadata { fetched_at: now - 7200, metadata: metadata })
+            .expect("Store should succeed.");
+        assert!(cache.discover(&reqwest::Client::new(), &None, endpoint).is_err());
+    }
+}
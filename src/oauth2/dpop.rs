@@ -0,0 +1,90 @@
+use crate::jws::{self, Algorithm, PublicJwk};
+use crate::oauth2::{self, Credentials, GrantError, Store};
+
+use reqwest::Url;
+use ring::digest;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// https://tools.ietf.org/html/rfc9449
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct DpopHeader {
+    typ: String,
+    alg: Algorithm,
+    jwk: PublicJwk,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct DpopClaims {
+    jti: String,
+    htm: String,
+    htu: String,
+    iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ath: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+}
+
+impl Store {
+    /// A DPoP proof for a `method` request to `url`, bound to
+    /// `access_token` if given. None if DPoP is disabled, or the
+    /// credentials have no key to prove possession of.
+    pub fn proof(&self, method: &str, url: &str, access_token: Option<&str>, nonce: Option<String>) -> Result<Option<String>, GrantError> {
+        if !self.endpoints()?.dpop {
+            return Ok(None);
+        }
+        let key = match &self.configuration.credentials {
+            Credentials::Assertion(assertion) => &assertion.key,
+            _ => return Ok(None),
+        };
+        let header = DpopHeader {
+            typ: "dpop+jwt".to_string(),
+            alg: key.algorithm(),
+            jwk: key.public_jwk().map_err(|e| GrantError::JwtSignError(e))?,
+        };
+        let claims = DpopClaims {
+            jti: oauth2::random(16)?,
+            htm: method.to_string(),
+            htu: htu(url)?,
+            iat: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|e| GrantError::ClockError(e))?
+                .as_secs() as i64,
+            ath: access_token.map(ath),
+            nonce: nonce,
+        };
+        jws::encode(key, &header, &claims).map(Some).map_err(|e| GrantError::JwtSignError(e))
+    }
+}
+
+/// The request url without query or fragment.
+fn htu(url: &str) -> Result<String, GrantError> {
+    let mut url = Url::parse(url).map_err(|_e| GrantError::InvalidEndpointError(url.to_string()))?;
+    url.set_query(None);
+    url.set_fragment(None);
+    Ok(url.to_string())
+}
+
+/// Hash of the access token the proof is presented with.
+fn ath(access_token: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, access_token.as_bytes());
+    base64::encode_config(hash.as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_htu() {
+        let htu = htu("https://api.smith.st/issue?a=b#c").expect("Url should parse.");
+        assert_eq!(htu, "https://api.smith.st/issue");
+    }
+
+    #[test]
+    fn test_ath() {
+        // https://tools.ietf.org/html/rfc9449#section-7.1
+        assert_eq!(ath("Kz~8mXK1EalYznwH-LC-1fBAo.4Ljp~zsPE_NeO.gxU"), "fUHyO2r2Z3DZ53EsNrWBb0xWXoaNy59IiKCAqksmQEo");
+    }
+}
@@ -0,0 +1,32 @@
+use crate::oauth2::{self, AccessTokenError, GrantError, Store};
+use crate::transport::Request;
+
+// https://tools.ietf.org/html/rfc7009
+
+impl Store {
+    /// Revoke `token`, `hint` is its type, `access_token` or
+    /// `refresh_token`. Revoking an unknown token succeeds.
+    pub fn revoke(&self, token: &str, hint: &str) -> Result<(), GrantError> {
+        let endpoint = self.endpoints()?.revocation.ok_or(GrantError::RevocationUnsupportedError)?;
+        let parameters = vec![
+            ("token", token.to_string()),
+            ("token_type_hint", hint.to_string()),
+            ("client_id", self.configuration.client_id.clone()),
+        ];
+        let response = self.send(Request::form(oauth2::url(&endpoint)?, &parameters))?;
+
+        match response.status {
+            reqwest::StatusCode::OK => {
+                Ok(())
+            },
+            reqwest::StatusCode::BAD_REQUEST => {
+                let error: AccessTokenError = serde_json::from_slice(&response.body)
+                    .map_err(|e| GrantError::Json400ParseError(e))?;
+                Err(GrantError::AccessTokenError(error))
+            },
+            s => {
+                Err(GrantError::InvalidStatusCodeError(s))
+            },
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use crate::file;
+use crate::oauth2::{AccessToken, AccessTokenResponse, AccessTokenState};
+use crate::oauth2::cache::CacheLock;
+
+use std::fs::{DirBuilder, File};
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Tokens from an interactive login, persisted between invocations.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub access_token: String,
+    pub expires_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    IoError(std::io::Error),
+    JsonError(serde_json::Error),
+}
+
+impl From<std::io::Error> for SessionError {
+    fn from(err: std::io::Error) -> SessionError {
+        SessionError::IoError(err)
+    }
+}
+
+impl Session {
+    pub fn from_response(response: &AccessTokenResponse, requested_at: SystemTime) -> Session {
+        let expires_at = requested_at.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs() + response.expires_in;
+        Session {
+            access_token: response.access_token.clone(),
+            expires_at: expires_at,
+            refresh_token: response.refresh_token.clone(),
+        }
+    }
+
+    /// A refresh response may omit the refresh token, in which case the
+    /// current one remains valid.
+    pub fn refreshed(&self, response: &AccessTokenResponse, requested_at: SystemTime) -> Session {
+        let mut session = Session::from_response(response, requested_at);
+        if session.refresh_token.is_none() {
+            session.refresh_token = self.refresh_token.clone();
+        }
+        session
+    }
+
+    pub fn state(&self) -> AccessTokenState {
+        AccessTokenState {
+            token: AccessToken { value: self.access_token.clone() },
+            expires_at: UNIX_EPOCH + Duration::from_secs(self.expires_at),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Session>, SessionError> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) =>
+                if err.kind() == ErrorKind::NotFound {
+                    return Ok(None);
+                } else {
+                    return Err(SessionError::IoError(err));
+                }
+        };
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        serde_json::from_str(&contents).map(Some).map_err(|e| SessionError::JsonError(e))
+    }
+
+    /// Block until no other process is refreshing the session at `path`,
+    /// so a rotated refresh token is only used once.
+    pub fn lock(path: &Path) -> Result<CacheLock, SessionError> {
+        if let Some(parent) = path.parent() {
+            DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+        }
+        let lock = CacheLock::acquire(&path.with_extension("lock"))?;
+        Ok(lock)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SessionError> {
+        if let Some(parent) = path.parent() {
+            DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+        }
+        let contents = serde_json::to_vec(self).map_err(|e| SessionError::JsonError(e))?;
+        file::write_atomic(path, &contents, &file::Options::private())?;
+        Ok(())
+    }
+}
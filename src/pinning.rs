@@ -0,0 +1,339 @@
+use crate::http;
+use crate::transport::{Request, Response};
+
+use futures::future::{self, Future};
+use futures::stream::Stream;
+use futures::sync::oneshot;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Uri};
+use hyper_openssl::HttpsConnector;
+use openssl::pkcs12::Pkcs12;
+use openssl::ssl::{SslConnector, SslConnectorBuilder, SslMethod, SslVerifyMode};
+use openssl::x509::X509StoreContextRef;
+use reqwest::Url;
+use ring::digest;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::runtime::{self, Runtime, TaskExecutor};
+use tokio::timer::Timeout;
+
+// https://tools.ietf.org/html/rfc7469#section-2.4
+
+/// A future that can be spawned on a tokio runtime.
+pub type PinnedResponse = Box<dyn Future<Item = Response, Error = PinError> + Send>;
+
+/// Public key pins for the smith hosts. Requests to pinned hosts are
+/// sent by a client of their own, which checks the chain presented
+/// while verifying the certificate, so a connection is refused during
+/// the handshake unless it has a pinned key. The http client can't be
+/// given that check, and a proxy would make the handshake with the
+/// host itself, so pins can't be used with a proxy.
+pub struct Pinning {
+    /// Base64 SHA-256 hashes of acceptable SubjectPublicKeyInfos.
+    pins: Arc<Vec<String>>,
+    timeout: Duration,
+    ca_bundle: Option<PathBuf>,
+    identity: Option<(PathBuf, String)>,
+    /// Why the latest handshake with each host was refused, OpenSSL
+    /// only reports that verification failed.
+    refused: Arc<Mutex<HashMap<String, PinError>>>,
+    /// Both are started on first use, they start threads.
+    client: Mutex<Option<Client<HttpsConnector<HttpConnector>>>>,
+    runtime: Mutex<Option<Runtime>>,
+}
+
+#[derive(Debug)]
+pub enum PinError {
+    InvalidPinError(String),
+    /// At least one pin must be a backup, for a key not yet in use,
+    /// so the key can be rotated without locking clients out.
+    NoBackupPinError,
+    /// Every pin is for a key the host presented, so none is a backup.
+    BackupPinInUseError(String),
+    InvalidUrlError(String),
+    /// Pins are checked on a direct connection, which a proxy would
+    /// bypass.
+    ProxyError,
+    /// Pinned hosts are only reached over https.
+    InsecureUrlError(String),
+    TlsError(String),
+    RuntimeError(std::io::Error),
+    RequestError(String, hyper::Error),
+    TimeoutError(String),
+    PinMismatchError(String, Vec<String>),
+}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PinError::InvalidPinError(pin) =>
+              write!(f, "Pin '{}' is not valid, it should be 'sha256/' followed by a base64 SHA-256 hash.", pin),
+            PinError::NoBackupPinError =>
+              write!(f, "At least two pins are required, including a backup pin for key rotation."),
+            PinError::BackupPinInUseError(host) =>
+              write!(f, "The TLS certificate for {} matches every configured pin, one must be a backup for a key not yet in use.", host),
+            PinError::InvalidUrlError(url) =>
+              write!(f, "Url '{}' is not valid.", url),
+            PinError::ProxyError =>
+              write!(f, "Pins can't be verified through a proxy, unset SMITH_PINS or the proxy variables."),
+            PinError::InsecureUrlError(url) =>
+              write!(f, "Refusing to connect to pinned host without TLS, tried: {}", url),
+            PinError::TlsError(_) =>
+              write!(f, "TLS for pinned hosts could not be configured, check the CA bundle and client certificate."),
+            PinError::RuntimeError(_) =>
+              write!(f, "Requests to pinned hosts could not be started."),
+            PinError::RequestError(host, _) =>
+              write!(f, "Request to {} failed, pinned hosts must be reachable without a proxy.", host),
+            PinError::TimeoutError(host) =>
+              write!(f, "Request to {} timed out.", host),
+            PinError::PinMismatchError(host, _) =>
+              write!(f, "The TLS certificate for {} does not match the configured pins, refusing to connect.", host),
+        }
+    }
+}
+
+impl PinError {
+    /// Whether the request may succeed if retried, a refused
+    /// certificate won't be accepted on another try.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            PinError::RequestError(_, _) | PinError::TimeoutError(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Pinning {
+    /// Pin the smith hosts to `pins`, in the `sha256/<base64>` form,
+    /// connecting as configured by `settings`, which must not have a
+    /// proxy.
+    pub fn new(pins: &[String], settings: &http::Settings) -> Result<Pinning, PinError> {
+        let mut parsed = vec![];
+        for pin in pins {
+            let hash = if pin.starts_with("sha256/") { &pin["sha256/".len()..] } else { "" };
+            match base64::decode(hash) {
+                Ok(ref bytes) if bytes.len() == 32 => parsed.push(hash.to_string()),
+                _ => return Err(PinError::InvalidPinError(pin.clone())),
+            }
+        }
+        parsed.sort();
+        parsed.dedup();
+        if parsed.len() < 2 {
+            return Err(PinError::NoBackupPinError);
+        }
+        if settings.proxies.is_configured() {
+            return Err(PinError::ProxyError);
+        }
+        Ok(Pinning {
+            pins: Arc::new(parsed),
+            timeout: settings.timeout,
+            ca_bundle: settings.ca_bundle.clone(),
+            identity: settings.identity.clone(),
+            refused: Arc::new(Mutex::new(HashMap::new())),
+            client: Mutex::new(None),
+            runtime: Mutex::new(None),
+        })
+    }
+
+    /// Send `request`, blocking until the whole response is read.
+    pub fn send(&self, request: Request) -> Result<Response, PinError> {
+        let executor = self.executor()?;
+        let (sender, receiver) = oneshot::channel();
+        executor.spawn(self.send_async(request).then(move |result| sender.send(result).map_err(|_result| ())));
+        receiver
+            .wait()
+            .unwrap_or_else(|_canceled| Err(PinError::RuntimeError(std::io::Error::new(std::io::ErrorKind::Other, "runtime stopped"))))
+    }
+
+    /// Send `request`, the response is read in full. The future needs
+    /// a tokio runtime, for the connection and the timeout.
+    pub fn send_async(&self, request: Request) -> PinnedResponse {
+        let host = match pinned_host(&request.url) {
+            Ok(host) => host,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let client = match self.client() {
+            Ok(client) => client,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let uri: Uri = match request.url.as_str().parse() {
+            Ok(uri) => uri,
+            Err(_) => return Box::new(future::err(PinError::InvalidUrlError(request.url.to_string()))),
+        };
+        let mut outgoing = hyper::Request::new(Body::from(request.body.unwrap_or_default()));
+        *outgoing.method_mut() = request.method;
+        *outgoing.uri_mut() = uri;
+        *outgoing.headers_mut() = request.headers;
+        let refused = self.refused.clone();
+        let timed_out = host.clone();
+        let response = client
+            .request(outgoing)
+            .and_then(|response| {
+                let (parts, body) = response.into_parts();
+                body.concat2().map(move |body| Response { status: parts.status, headers: parts.headers, body: body.to_vec() })
+            })
+            .map_err(move |e| lock(&refused).remove(&host).unwrap_or_else(|| PinError::RequestError(host, e)));
+        Box::new(Timeout::new(response, self.timeout).map_err(move |e| e.into_inner().unwrap_or_else(|| PinError::TimeoutError(timed_out))))
+    }
+
+    fn client(&self) -> Result<Client<HttpsConnector<HttpConnector>>, PinError> {
+        let mut client = lock(&self.client);
+        if let Some(ref client) = *client {
+            return Ok(client.clone());
+        }
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|e| PinError::TlsError(e.to_string()))?;
+        if let Some(ref ca_bundle) = self.ca_bundle {
+            builder.set_ca_file(ca_bundle).map_err(|e| PinError::TlsError(e.to_string()))?;
+        }
+        self.set_identity(&mut builder).map_err(|e| PinError::TlsError(e.to_string()))?;
+        let mut http = HttpConnector::new(1);
+        http.enforce_http(false);
+        let mut https = HttpsConnector::with_connector(http, builder).map_err(|e| PinError::TlsError(e.to_string()))?;
+        let (pins, refused) = (self.pins.clone(), self.refused.clone());
+        https.set_callback(move |configuration, destination| {
+            let (pins, refused, host) = (pins.clone(), refused.clone(), destination.host().to_lowercase());
+            configuration.set_verify_callback(SslVerifyMode::PEER, move |verified, context| verify(&pins, &refused, &host, verified, context));
+            Ok(())
+        });
+        let built = Client::builder().build(https);
+        *client = Some(built.clone());
+        Ok(built)
+    }
+
+    /// The runtime blocking sends are made on, with a single worker
+    /// as they are waited for one at a time per thread.
+    fn executor(&self) -> Result<TaskExecutor, PinError> {
+        let mut runtime = lock(&self.runtime);
+        if runtime.is_none() {
+            *runtime = Some(runtime::Builder::new()
+                .core_threads(1)
+                .name_prefix("smith-pinning-")
+                .build()
+                .map_err(|e| PinError::RuntimeError(e))?);
+        }
+        Ok(runtime.as_ref().map(|runtime| runtime.executor()).expect("Runtime was just started."))
+    }
+
+    fn set_identity(&self, builder: &mut SslConnectorBuilder) -> Result<(), Box<dyn std::error::Error>> {
+        let (path, password) = match self.identity {
+            Some((ref path, ref password)) => (path, password),
+            None => return Ok(()),
+        };
+        let identity = Pkcs12::from_der(&std::fs::read(path)?)?.parse(password)?;
+        builder.set_certificate(&identity.cert)?;
+        builder.set_private_key(&identity.pkey)?;
+        if let Some(chain) = identity.chain {
+            for certificate in chain {
+                builder.add_extra_chain_cert(certificate)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The host of `url`, which must be https.
+fn pinned_host(url: &Url) -> Result<String, PinError> {
+    if url.scheme() != "https" {
+        return Err(PinError::InsecureUrlError(url.to_string()));
+    }
+    url.host_str().map(|host| host.to_lowercase()).ok_or_else(|| PinError::InvalidUrlError(url.to_string()))
+}
+
+/// Called by OpenSSL for each certificate in the chain from the root
+/// down, with whether it verified. Once the leaf has, the chain must
+/// have a pinned key, and mustn't have every one.
+fn verify(pins: &[String], refused: &Mutex<HashMap<String, PinError>>, host: &str, verified: bool, context: &mut X509StoreContextRef) -> bool {
+    if !verified || context.error_depth() != 0 {
+        return verified;
+    }
+    let presented = match context.chain() {
+        Some(chain) => chain
+            .iter()
+            .filter_map(|certificate| certificate.public_key().and_then(|key| key.public_key_to_der()).ok())
+            .map(|spki| pin(&spki))
+            .collect(),
+        None => vec![],
+    };
+    match refusal(pins, host, presented) {
+        Some(error) => {
+            lock(refused).insert(host.to_string(), error);
+            false
+        },
+        None => {
+            lock(refused).remove(host);
+            true
+        },
+    }
+}
+
+/// Why a chain with the `presented` keys is refused, if it is.
+fn refusal(pins: &[String], host: &str, presented: Vec<String>) -> Option<PinError> {
+    let matched = pins.iter().filter(|pin| presented.contains(pin)).count();
+    if matched == 0 {
+        Some(PinError::PinMismatchError(host.to_string(), presented))
+    } else if matched == pins.len() {
+        Some(PinError::BackupPinInUseError(host.to_string()))
+    } else {
+        None
+    }
+}
+
+/// The pin for a DER encoded SubjectPublicKeyInfo.
+pub fn pin(spki: &[u8]) -> String {
+    base64::encode(digest::digest(&digest::SHA256, spki).as_ref())
+}
+
+/// Locks are only held for simple assignments, so a poisoned lock
+/// still holds consistent data.
+fn lock<A>(mutex: &Mutex<A>) -> MutexGuard<A> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use reqwest::Method;
+    use reqwest::header::HeaderMap;
+
+    fn pins() -> Vec<String> {
+        vec![format!("sha256/{}", pin(b"primary")), format!("sha256/{}", pin(b"backup"))]
+    }
+
+    #[test]
+    fn test_new() {
+        let settings = http::Settings::default();
+        assert!(Pinning::new(&pins(), &settings).is_ok());
+        assert!(match Pinning::new(&pins()[..1], &settings) { Err(PinError::NoBackupPinError) => true, _ => false });
+        let repeated = vec![pins()[0].clone(), pins()[0].clone()];
+        assert!(match Pinning::new(&repeated, &settings) { Err(PinError::NoBackupPinError) => true, _ => false });
+        let invalid = vec![pins()[0].clone(), "sha1/AAAA".to_string()];
+        assert!(match Pinning::new(&invalid, &settings) { Err(PinError::InvalidPinError(_)) => true, _ => false });
+        let mut proxied = http::Settings::default();
+        proxied.proxies.https = Some(Url::parse("http://proxy:3128").expect("Proxy should be valid."));
+        assert!(match Pinning::new(&pins(), &proxied) { Err(PinError::ProxyError) => true, _ => false });
+    }
+
+    #[test]
+    fn test_send_insecure() {
+        let pinning = Pinning::new(&pins(), &http::Settings::default()).expect("Pins should be valid.");
+        let request = Request {
+            method: Method::GET,
+            url: Url::parse("http://api.smith.st/userinfo").expect("Url should be valid."),
+            headers: HeaderMap::new(),
+            body: None,
+        };
+        assert!(match pinning.send(request) { Err(PinError::InsecureUrlError(_)) => true, _ => false });
+    }
+
+    #[test]
+    fn test_refusal() {
+        let pins = vec![pin(b"primary"), pin(b"backup")];
+        assert!(refusal(&pins, "api.smith.st", vec![pin(b"leaf"), pin(b"primary")]).is_none());
+        assert!(match refusal(&pins, "api.smith.st", vec![pin(b"leaf"), pin(b"root")]) { Some(PinError::PinMismatchError(_, _)) => true, _ => false });
+        assert!(match refusal(&pins, "api.smith.st", vec![pin(b"backup"), pin(b"primary")]) { Some(PinError::BackupPinInUseError(_)) => true, _ => false });
+    }
+}
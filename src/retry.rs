@@ -0,0 +1,190 @@
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::thread;
+use std::time::Duration;
+
+/// How failed requests are retried, the delay before each retry grows
+/// exponentially from `base` up to `max`, with full jitter so clients
+/// that failed together don't retry together.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Policy {
+    /// Retries after the first attempt, zero disables retrying.
+    pub retries: u32,
+    pub base: Duration,
+    pub max: Duration,
+}
+
+/// A failed attempt.
+#[derive(Debug)]
+pub enum Attempt<E> {
+    /// Worth retrying, after the delay the server asked for if any.
+    Retry(E, Option<Duration>),
+    Fail(E),
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            retries: 3,
+            base: Duration::from_millis(250),
+            max: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Policy {
+    pub fn disabled() -> Policy {
+        Policy { retries: 0, ..Policy::default() }
+    }
+
+    /// Run `f` until it succeeds, fails permanently, or the retries
+    /// are used up. A server asking for a longer wait than `max` is
+    /// not retried.
+    pub fn run<T, E, F>(&self, mut f: F) -> Result<T, E>
+        where F: FnMut() -> Result<T, Attempt<E>> {
+        let mut attempt = 0;
+        loop {
+            let (error, retry_after) = match f() {
+                Ok(value) => return Ok(value),
+                Err(Attempt::Retry(error, retry_after)) => (error, retry_after),
+                Err(Attempt::Fail(error)) => return Err(error),
+            };
+            match self.delay(attempt, retry_after) {
+                Some(delay) => thread::sleep(delay),
+                None => return Err(error),
+            }
+            attempt += 1;
+        }
+    }
+
+    /// The delay before retrying failed `attempt`, none once the
+    /// retries are used up.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt >= self.retries || retry_after.map(|after| after > self.max).unwrap_or(false) {
+            return None;
+        }
+        Some(retry_after.unwrap_or_else(|| self.backoff(attempt)))
+    }
+
+    /// A random delay of up to `base * 2^attempt`, capped at `max`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let cap = self.cap(attempt);
+        random_up_to(cap).unwrap_or(cap)
+    }
+
+    /// Like `backoff`, but at least half of the capped delay, for
+    /// loops that shouldn't retry straight away.
+    pub fn backoff_floored(&self, attempt: u32) -> Duration {
+        jitter(self.cap(attempt))
+    }
+
+    fn cap(&self, attempt: u32) -> Duration {
+        self.base
+            .checked_mul(1 << std::cmp::min(attempt, 16))
+            .map(|delay| std::cmp::min(delay, self.max))
+            .unwrap_or(self.max)
+    }
+}
+
+/// A random delay between half and all of `delay`, so that clients
+/// scheduled together spread out.
+pub fn jitter(delay: Duration) -> Duration {
+    let half = delay / 2;
+    half + random_up_to(delay - half).unwrap_or_default()
+}
+
+fn random_up_to(max: Duration) -> Option<Duration> {
+    let mut bytes = [0u8; 8];
+    SystemRandom::new().fill(&mut bytes).ok()?;
+    let random = bytes.iter().fold(0u64, |n, b| (n << 8) | u64::from(*b));
+    Some(Duration::from_millis(random % (max.as_millis() as u64 + 1)))
+}
+
+/// The delay from a `Retry-After` header, only delay-seconds are
+/// understood, an HTTP-date falls back to backoff.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Is the status one that a later attempt may succeed for.
+pub fn is_retryable(status: reqwest::StatusCode) -> bool {
+    match status {
+        reqwest::StatusCode::TOO_MANY_REQUESTS
+            | reqwest::StatusCode::BAD_GATEWAY
+            | reqwest::StatusCode::SERVICE_UNAVAILABLE
+            | reqwest::StatusCode::GATEWAY_TIMEOUT => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_jitter() {
+        let delay = Duration::from_secs(60);
+        for _ in 0..40 {
+            let jittered = jitter(delay);
+            assert!(jittered >= delay / 2 && jittered <= delay);
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = Policy::default();
+        for attempt in 0..40 {
+            let cap = std::cmp::min(policy.base * (1 << std::cmp::min(attempt, 16)), policy.max);
+            assert!(policy.backoff(attempt) <= cap);
+            let floored = policy.backoff_floored(attempt);
+            assert!(floored >= cap / 2 && floored <= cap);
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let policy = Policy { retries: 2, base: Duration::from_millis(1), max: Duration::from_millis(10) };
+        let mut attempts = 0;
+        let result: Result<u32, &str> = policy.run(|| {
+            attempts += 1;
+            if attempts < 3 { Err(Attempt::Retry("unavailable", None)) } else { Ok(attempts) }
+        });
+        assert_eq!(result, Ok(3));
+
+        let mut attempts = 0;
+        let result: Result<u32, &str> = policy.run(|| {
+            attempts += 1;
+            Err(Attempt::Retry("unavailable", None))
+        });
+        assert_eq!((result, attempts), (Err("unavailable"), 3));
+
+        let mut attempts = 0;
+        let result: Result<u32, &str> = policy.run(|| {
+            attempts += 1;
+            Err(Attempt::Fail("invalid"))
+        });
+        assert_eq!((result, attempts), (Err("invalid"), 1));
+
+        let mut attempts = 0;
+        let result: Result<u32, &str> = policy.run(|| {
+            attempts += 1;
+            Err(Attempt::Retry("rate limited", Some(Duration::from_secs(60))))
+        });
+        assert_eq!((result, attempts), (Err("rate limited"), 1));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(retry_after(&headers), None);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::api::Error;
+use crate::pinning::Pinning;
+
+#[cfg(feature = "async")]
+use futures::{Future, Stream};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use reqwest::{Method, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use url::form_urlencoded;
+
+/// How `Api` sends requests, `ReqwestTransport` by default,
+/// `PinnedTransport` when pins are configured, or `ScriptedTransport`
+/// to test without a smith server.
+pub trait Transport: Send + Sync {
+    fn send(&self, request: Request) -> Result<Response, Error>;
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub url: Url,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// A POST of the form `parameters` to `url`, accepting JSON.
+    pub fn form(url: Url, parameters: &[(&str, String)]) -> Request {
+        let body = form_urlencoded::Serializer::new(String::new()).extend_pairs(parameters).finish();
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-www-form-urlencoded"));
+        Request { method: Method::POST, url, headers, body: Some(body.into_bytes()) }
+    }
+}
+
+impl Response {
+    pub fn new(status: StatusCode, body: Vec<u8>) -> Response {
+        Response { status, headers: HeaderMap::new(), body }
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        serde_json::from_slice(&self.body).map_err(|e| Error::CouldNotParseResponse(e))
+    }
+}
+
+pub struct ReqwestTransport {
+    pub client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(client: reqwest::Client) -> ReqwestTransport {
+        ReqwestTransport { client }
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn send(&self, request: Request) -> Result<Response, Error> {
+        send_with(&self.client, request).map_err(|e| Error::RequestError(e))
+    }
+}
+
+/// Sends requests to pinned hosts, used by `Api` when pins are
+/// configured.
+pub struct PinnedTransport {
+    pub pins: Arc<Pinning>,
+}
+
+impl PinnedTransport {
+    pub fn new(pins: Arc<Pinning>) -> PinnedTransport {
+        PinnedTransport { pins }
+    }
+}
+
+impl Transport for PinnedTransport {
+    fn send(&self, request: Request) -> Result<Response, Error> {
+        self.pins.send(request).map_err(|e| Error::PinningError(e))
+    }
+}
+
+/// Send `request` with `client`, reading the whole response.
+pub fn send_with(client: &reqwest::Client, request: Request) -> Result<Response, reqwest::Error> {
+    let mut builder = client
+        .request(request.method, request.url)
+        .headers(request.headers);
+    if let Some(body) = request.body {
+        builder = builder.body(body);
+    }
+    let mut response = builder.send()?;
+    let mut body = vec![];
+    response.copy_to(&mut body)?;
+    Ok(Response { status: response.status(), headers: response.headers().clone(), body })
+}
+
+/// Send `request` with the async `client`, reading the whole response.
+#[cfg(feature = "async")]
+pub fn send_async_with(client: &reqwest::r#async::Client, request: Request) -> impl Future<Item = Response, Error = reqwest::Error> {
+    let mut builder = client
+        .request(request.method, request.url)
+        .headers(request.headers);
+    if let Some(body) = request.body {
+        builder = builder.body(body);
+    }
+    builder.send().and_then(|response| {
+        let (status, headers) = (response.status(), response.headers().clone());
+        response.into_body().concat2().map(move |body| Response { status, headers, body: body.to_vec() })
+    })
+}
+
+/// Responds to requests from a script, in order, recording the
+/// requests made. Requests nothing in the script matches get a 404.
+pub struct ScriptedTransport {
+    script: Mutex<VecDeque<(Method, String, Response)>>,
+    requests: Mutex<Vec<Request>>,
+}
+
+impl ScriptedTransport {
+    pub fn new() -> ScriptedTransport {
+        ScriptedTransport { script: Mutex::new(VecDeque::new()), requests: Mutex::new(vec![]) }
+    }
+
+    /// Respond to the next `method` request for `path` with `response`.
+    pub fn respond(self, method: Method, path: &str, response: Response) -> ScriptedTransport {
+        self.script.lock().unwrap_or_else(|e| e.into_inner()).push_back((method, path.to_string(), response));
+        self
+    }
+
+    /// Respond to the next `method` request for `path` with `body` as
+    /// JSON.
+    pub fn respond_json<T: Serialize>(self, method: Method, path: &str, status: StatusCode, body: &T) -> ScriptedTransport {
+        let body = serde_json::to_vec(body).unwrap_or_default();
+        self.respond(method, path, Response::new(status, body))
+    }
+
+    /// The requests made so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Is every scripted response used.
+    pub fn is_done(&self) -> bool {
+        self.script.lock().unwrap_or_else(|e| e.into_inner()).is_empty()
+    }
+}
+
+impl Default for ScriptedTransport {
+    fn default() -> ScriptedTransport {
+        ScriptedTransport::new()
+    }
+}
+
+impl Transport for ScriptedTransport {
+    fn send(&self, request: Request) -> Result<Response, Error> {
+        let mut script = self.script.lock().unwrap_or_else(|e| e.into_inner());
+        let next = script.iter().position(|(method, path, _)| *method == request.method && path == request.url.path());
+        self.requests.lock().unwrap_or_else(|e| e.into_inner()).push(request);
+        Ok(match next.and_then(|index| script.remove(index)) {
+            Some((_, _, response)) => response,
+            None => Response::new(StatusCode::NOT_FOUND, br#"{"error":"not-scripted"}"#.to_vec()),
+        })
+    }
+}